@@ -1,8 +1,18 @@
 use std::{
-  collections::HashMap,
+  borrow::Cow,
+  collections::{HashMap, VecDeque},
   ffi::{OsStr, OsString},
-  path::PathBuf,
-  sync::{Mutex, OnceLock},
+  io::{self, Read, Write},
+  os::unix::ffi::{OsStrExt, OsStringExt},
+  path::{Path, PathBuf},
+  process,
+  sync::{
+    Mutex,
+    OnceLock,
+    mpsc::{self, RecvTimeoutError},
+  },
+  thread::{self, JoinHandle},
+  time::Duration,
 };
 
 use color_eyre::{
@@ -10,46 +20,409 @@ use color_eyre::{
   eyre::{self, Context, bail},
 };
 use secrecy::{ExposeSecret, SecretString};
-use subprocess::{Exec, ExitStatus, Redirection};
+use subprocess::{Exec, ExitStatus, NullFile, Popen, Redirection};
 use thiserror::Error;
 use tracing::{debug, info, warn};
 use which::which;
 
-use crate::{installable::Installable, interface::NixBuildPassthroughArgs};
+use crate::{
+  installable::Installable,
+  interface::{CopyArgs, NixBuildPassthroughArgs},
+  interrupt,
+  ssh::SshTarget,
+};
+
+/// How elevation was authenticated on a remote host, cached per host and
+/// elevation program so that the user is asked at most once.
+#[derive(Debug, Clone)]
+enum RemoteAuth {
+  /// The password fed to `sudo --stdin`.
+  Password(SecretString),
+  /// The program was verified to work without a password.
+  Passwordless,
+}
 
-static PASSWORD_CACHE: OnceLock<Mutex<HashMap<String, SecretString>>> =
+static PASSWORD_CACHE: OnceLock<Mutex<HashMap<String, RemoteAuth>>> =
   OnceLock::new();
 
-fn get_cached_password(host: &str) -> Option<SecretString> {
+/// Held while authenticating on a remote host, so that deployments to
+/// several hosts at once ask for one password at a time.
+static AUTH_LOCK: Mutex<()> = Mutex::new(());
+
+fn cache_key(host: &str, program: &str) -> String {
+  format!("{program}@{host}")
+}
+
+fn get_cached_auth(host: &str, program: &str) -> Option<RemoteAuth> {
   let cache = PASSWORD_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
   let guard = cache.lock().unwrap_or_else(|e| e.into_inner());
-  guard.get(host).cloned()
+  guard.get(&cache_key(host, program)).cloned()
 }
 
-fn cache_password(host: &str, password: SecretString) {
+fn cache_auth(host: &str, program: &str, auth: RemoteAuth) {
   let cache = PASSWORD_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
   let mut guard = cache.lock().unwrap_or_else(|e| e.into_inner());
-  guard.insert(host.to_string(), password);
+  guard.insert(cache_key(host, program), auth);
 }
 
-fn ssh_wrap(
-  cmd: Exec,
-  ssh: Option<&str>,
-  password: Option<&SecretString>,
-) -> Exec {
-  if let Some(ssh) = ssh {
-    let mut ssh_cmd = Exec::cmd("ssh")
-      .arg("-T")
-      .arg(ssh)
-      .arg(cmd.to_cmdline_lossy());
+/// The elevation programs nh knows how to drive. Each one passes the
+/// environment to the elevated command in its own way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ElevationProgram {
+  Sudo,
+  Doas,
+  Run0,
+  Pkexec,
+  /// Any other program, assumed to run its arguments like sudo does.
+  Other,
+}
 
-    if let Some(pwd) = password {
-      ssh_cmd = ssh_cmd.stdin(format!("{}\n", pwd.expose_secret()).as_str());
+impl ElevationProgram {
+  fn from_path(path: &Path) -> Self {
+    match path.file_name().and_then(OsStr::to_str) {
+      Some("sudo") => Self::Sudo,
+      Some("doas") => Self::Doas,
+      Some("run0") => Self::Run0,
+      Some("pkexec") => Self::Pkexec,
+      _ => Self::Other,
     }
+  }
 
-    ssh_cmd
+  /// Returns the arguments that make the program fail instead of prompting
+  /// for a password. Only sudo can read a password from stdin, the others
+  /// would prompt on a terminal that does not exist over `ssh -T`.
+  fn non_interactive_args(self) -> &'static [&'static str] {
+    match self {
+      Self::Sudo => &["--prompt=", "--stdin"],
+      Self::Doas => &["-n"],
+      Self::Run0 => &["--no-ask-password"],
+      Self::Pkexec => &["--disable-internal-agent"],
+      Self::Other => &[],
+    }
+  }
+
+  /// Explains how to allow passwordless elevation with the program.
+  fn passwordless_hint(self) -> &'static str {
+    match self {
+      Self::Doas => {
+        "For doas, add a `permit nopass` rule for your user, for example \
+         through `security.doas.extraRules` on NixOS."
+      },
+      Self::Run0 | Self::Pkexec => {
+        "For run0 and pkexec, add a polkit rule that lets your user run \
+         commands as root without authentication."
+      },
+      Self::Sudo | Self::Other => {
+        "Configure it to allow your user to run commands as root without a \
+         password."
+      },
+    }
+  }
+
+  /// Builds the command line that runs `command` as root through `program`
+  /// with `env` set. On a remote host the program must not prompt for a
+  /// password.
+  fn command_line(
+    self,
+    program: &Path,
+    env: Vec<(String, String)>,
+    command: &OsStr,
+    remote: bool,
+  ) -> Result<CommandLine> {
+    let mut line = CommandLine::new(program);
+    if remote {
+      line = line.args(self.non_interactive_args());
+    }
+
+    let askpass = std::env::var("NH_SUDO_ASKPASS").ok();
+    if askpass.is_some() && self != Self::Sudo {
+      debug!("Ignoring NH_SUDO_ASKPASS, it only applies to sudo");
+    }
+
+    let assignments =
+      env.into_iter().map(|(key, value)| format!("{key}={value}"));
+
+    match self {
+      Self::Sudo => {
+        // Use NH_SUDO_ASKPASS program for sudo if present
+        if let (Some(askpass), false) = (askpass, remote) {
+          line.env.push(("SUDO_ASKPASS".to_string(), askpass));
+          line = line.arg("-A");
+        }
+        Ok(line.arg("env").args(assignments).arg(command))
+      },
+      // doas only keeps the caller's environment with `keepenv` in doas.conf,
+      // which nh cannot rely on
+      Self::Doas | Self::Other => {
+        Ok(line.arg("env").args(assignments).arg(command))
+      },
+      Self::Run0 => {
+        Ok(
+          line
+            .args(
+              assignments.map(|assignment| format!("--setenv={assignment}")),
+            )
+            .arg(command),
+        )
+      },
+      // pkexec clears the environment, PATH included, so both env and the
+      // command are given by absolute path. Paths on a remote host cannot be
+      // looked up from here, so env gets the system PATH to find it in.
+      Self::Pkexec if remote => {
+        Ok(
+          line
+            .arg("/usr/bin/env")
+            .arg(format!("PATH={REMOTE_SYSTEM_PATH}"))
+            .args(assignments)
+            .arg(command),
+        )
+      },
+      Self::Pkexec => {
+        let env_program =
+          which("env").context("Failed to find env for pkexec")?;
+        let command = which(command).with_context(|| {
+          format!(
+            "Failed to find the absolute path of {} for pkexec",
+            command.to_string_lossy()
+          )
+        })?;
+        Ok(line.arg(env_program).args(assignments).arg(command))
+      },
+    }
+  }
+}
+
+/// The `PATH` that commands elevated with pkexec get on a remote host: the
+/// system profile of `NixOS` and the usual directories elsewhere.
+const REMOTE_SYSTEM_PATH: &str = concat!(
+  "/run/wrappers/bin:/run/current-system/sw/bin:",
+  "/nix/var/nix/profiles/default/bin:/usr/local/bin:/usr/bin:/bin"
+);
+
+/// Returns the name of the elevation program to run on a remote host. The
+/// path it was found at locally may not exist there, so it is looked up in
+/// the `PATH` of the remote shell instead.
+fn remote_elevation_program(strategy: &ElevationStrategy) -> Result<String> {
+  let program = strategy
+    .resolve()
+    .context("Failed to resolve elevation program")?;
+
+  program
+    .file_name()
+    .and_then(|name| name.to_str())
+    .map(str::to_string)
+    .ok_or_else(|| eyre::eyre!("Failed to determine elevation program name"))
+}
+
+fn ssh_wrap(line: &CommandLine, ssh: Option<&SshTarget>) -> Exec {
+  let local = match ssh {
+    Some(ssh) => {
+      CommandLine::new("ssh")
+        .args(ssh.ssh_args())
+        .arg(line.to_remote_command())
+    },
+    None => line.clone(),
+  };
+
+  // Ctrl-C in the terminal must not reach the commands of a critical section
+  // either, so they start with SIGINT ignored
+  if interrupt::is_critical() {
+    CommandLine {
+      env:  local.env,
+      argv: interrupt::IGNORE_SIGINT
+        .iter()
+        .map(OsString::from)
+        .chain(local.argv)
+        .collect(),
+    }
+    .to_exec()
   } else {
-    cmd
+    local.to_exec()
+  }
+}
+
+/// Number of stderr lines kept to explain a failed command.
+const STDERR_TAIL_LINES: usize = 50;
+
+/// Longest line kept in an [`OutputTail`], so that progress bars that never
+/// print a newline cannot grow it without bound.
+const MAX_TAIL_LINE: usize = 4096;
+
+/// The last lines of a stream, kept while the stream is forwarded to the
+/// terminal.
+#[derive(Debug)]
+struct OutputTail {
+  lines:     VecDeque<String>,
+  partial:   Vec<u8>,
+  max_lines: usize,
+}
+
+impl OutputTail {
+  fn new(max_lines: usize) -> Self {
+    Self {
+      lines: VecDeque::with_capacity(max_lines),
+      partial: Vec::new(),
+      max_lines,
+    }
+  }
+
+  fn push(&mut self, chunk: &[u8]) {
+    for &byte in chunk {
+      match byte {
+        b'\n' => {
+          let line = String::from_utf8_lossy(&self.partial).into_owned();
+          self.partial.clear();
+          if self.lines.len() == self.max_lines {
+            self.lines.pop_front();
+          }
+          self.lines.push_back(line);
+        },
+        // Progress output redraws the current line
+        b'\r' => self.partial.clear(),
+        _ if self.partial.len() < MAX_TAIL_LINE => self.partial.push(byte),
+        _ => {},
+      }
+    }
+  }
+
+  fn into_string(mut self) -> String {
+    if !self.partial.is_empty() {
+      self.push(b"\n");
+    }
+    Vec::from(self.lines).join("\n")
+  }
+}
+
+/// Runs `exec` until it exits, writing `stdin` to it first.
+///
+/// With `keep_tail`, stderr is forwarded to the terminal as it arrives and
+/// its last lines are returned, otherwise the command inherits our stderr.
+/// Stdout is always inherited.
+fn run_streaming(
+  exec: Exec,
+  stdin: Option<&SecretString>,
+  keep_tail: bool,
+) -> Result<(ExitStatus, String)> {
+  let exec = if stdin.is_some() {
+    exec.stdin(Redirection::Pipe)
+  } else {
+    exec
+  };
+  let exec = if keep_tail {
+    exec.stderr(Redirection::Pipe)
+  } else {
+    exec
+  };
+
+  let (mut child, _forward) = interrupt::spawn(|| exec.popen(), Popen::pid)?;
+
+  if let Some(password) = stdin {
+    if let Some(mut pipe) = child.stdin.take() {
+      // The command may exit without reading, which is reported below
+      let _ = writeln!(pipe, "{}", password.expose_secret());
+    }
+  }
+
+  let mut tail = OutputTail::new(STDERR_TAIL_LINES);
+  if let Some(mut stderr) = child.stderr.take() {
+    let mut terminal = io::stderr();
+    let mut buf = [0; 8192];
+    loop {
+      let read = match stderr.read(&mut buf) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err).context("Failed to read command output"),
+      };
+      let _ = terminal.write_all(&buf[..read]);
+      tail.push(&buf[..read]);
+    }
+  }
+
+  let status = child.wait()?;
+  interrupt::check()?;
+  Ok((status, tail.into_string()))
+}
+
+/// How often [`SudoKeepAlive`] refreshes the sudo timestamp. sudo defaults
+/// to a timeout of 5 minutes.
+const SUDO_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps the sudo timestamp of the user fresh while it is alive, so that the
+/// elevated commands following a long build do not prompt again.
+#[derive(Debug)]
+pub struct SudoKeepAlive {
+  stop:   mpsc::Sender<()>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl SudoKeepAlive {
+  /// Authenticates with sudo now and refreshes the timestamp in the
+  /// background until the returned value is dropped. Returns `None` if the
+  /// elevation program is not sudo, as the others have no timestamp to
+  /// refresh.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if sudo cannot be found or authentication fails.
+  pub fn start(strategy: &ElevationStrategy) -> Result<Option<Self>> {
+    let program = strategy
+      .resolve()
+      .context("Failed to resolve elevation program")?;
+    if ElevationProgram::from_path(&program) != ElevationProgram::Sudo {
+      debug!(?program, "Not keeping credentials alive for this program");
+      return Ok(None);
+    }
+
+    let mut validate = process::Command::new(&program);
+    if let Ok(askpass) = std::env::var("NH_SUDO_ASKPASS") {
+      validate.env("SUDO_ASKPASS", askpass).arg("-A");
+    }
+    let status = validate
+      .arg("-v")
+      .status()
+      .context("Failed to run sudo -v")?;
+    if !status.success() {
+      bail!("Failed to authenticate with sudo ({status})");
+    }
+
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::spawn(move || {
+      while let Err(RecvTimeoutError::Timeout) =
+        stopped.recv_timeout(SUDO_REFRESH_INTERVAL)
+      {
+        let refreshed = process::Command::new(&program)
+          .args(["-n", "-v"])
+          .stdin(process::Stdio::null())
+          .stdout(process::Stdio::null())
+          .stderr(process::Stdio::null())
+          .status()
+          .is_ok_and(|status| status.success());
+
+        if !refreshed {
+          warn!(
+            "Failed to refresh the sudo timestamp, you may be asked for your \
+             password again"
+          );
+          break;
+        }
+        debug!("Refreshed the sudo timestamp");
+      }
+    });
+
+    Ok(Some(Self {
+      stop,
+      thread: Some(thread),
+    }))
+  }
+}
+
+impl Drop for SudoKeepAlive {
+  fn drop(&mut self) {
+    let _ = self.stop.send(());
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
   }
 }
 
@@ -66,6 +439,138 @@ pub enum EnvAction {
   Remove,
 }
 
+/// A command line after elevation has been applied: the program with its
+/// arguments, and the variables to set in its environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CommandLine {
+  env:  Vec<(String, String)>,
+  argv: Vec<OsString>,
+}
+
+impl CommandLine {
+  fn new<S: AsRef<OsStr>>(program: S) -> Self {
+    Self {
+      env:  Vec::new(),
+      argv: vec![program.as_ref().to_os_string()],
+    }
+  }
+
+  #[must_use]
+  fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+    self.argv.push(arg.as_ref().to_os_string());
+    self
+  }
+
+  #[must_use]
+  fn args<I>(mut self, args: I) -> Self
+  where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+  {
+    self
+      .argv
+      .extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+    self
+  }
+
+  fn to_exec(&self) -> Exec {
+    let mut exec = Exec::cmd(&self.argv[0]).args(&self.argv[1..]);
+    for (key, value) in &self.env {
+      exec = exec.env(key, value);
+    }
+    exec
+  }
+
+  /// Converts the command line to a [`process::Command`], passing every
+  /// argument as is.
+  fn to_command(&self) -> process::Command {
+    let mut command = process::Command::new(&self.argv[0]);
+    command.args(&self.argv[1..]).envs(self.env.iter().cloned());
+    command
+  }
+
+  /// Renders the command line as the single string ssh hands to the remote
+  /// shell, quoted so that the shell splits it back into exactly this argv
+  /// and environment.
+  fn to_remote_command(&self) -> OsString {
+    let env = self.env.iter().map(|(key, value)| {
+      let mut word = OsString::from(format!("{key}="));
+      word.push(quote_os(OsStr::new(value), false));
+      word
+    });
+
+    // A leading word with `=` would be read as an assignment
+    let argv =
+      self.argv.iter().enumerate().map(|(i, arg)| {
+        quote_os(arg, i == 0 && arg.as_bytes().contains(&b'='))
+      });
+
+    let mut command = OsString::new();
+    for (i, word) in env.chain(argv).enumerate() {
+      if i > 0 {
+        command.push(" ");
+      }
+      command.push(word);
+    }
+    command
+  }
+
+  /// Renders the command line as a POSIX shell command, with environment
+  /// assignments in front of the program.
+  fn to_shell(&self) -> String {
+    let env = self
+      .env
+      .iter()
+      .map(|(key, value)| format!("{key}={}", shell_quote(value)));
+
+    let argv = self.argv.iter().enumerate().map(|(i, arg)| {
+      let arg = arg.to_string_lossy();
+      // A leading word with `=` would be read as an assignment
+      if i == 0 && arg.contains('=') {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+      } else {
+        shell_quote(&arg).into_owned()
+      }
+    });
+
+    env.chain(argv).collect::<Vec<_>>().join(" ")
+  }
+}
+
+/// Quotes a word for a POSIX shell like [`shell_quote`], keeping bytes that
+/// are not valid UTF-8 as they are. With `force`, the word is quoted even if
+/// it does not need to be.
+fn quote_os(word: &OsStr, force: bool) -> OsString {
+  if let (Some(word), false) = (word.to_str(), force) {
+    return OsString::from(shell_quote(word).into_owned());
+  }
+
+  let mut quoted = vec![b'\''];
+  for &byte in word.as_bytes() {
+    if byte == b'\'' {
+      quoted.extend_from_slice(b"'\\''");
+    } else {
+      quoted.push(byte);
+    }
+  }
+  quoted.push(b'\'');
+  OsString::from_vec(quoted)
+}
+
+/// Quotes a word for a POSIX shell, leaving it as is if no quoting is needed.
+pub fn shell_quote(word: &str) -> Cow<'_, str> {
+  let is_safe = |c: char| {
+    c.is_ascii_alphanumeric()
+      || matches!(c, '-' | '_' | '.' | ',' | '/' | ':' | '=' | '@' | '+' | '%')
+  };
+
+  if !word.is_empty() && word.chars().all(is_safe) {
+    Cow::Borrowed(word)
+  } else {
+    Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+  }
+}
+
 /// Strategy for choosing a privilege elevation program.
 /// - `Auto`: try supported programs in fallback order.
 /// - `Prefer(PathBuf)`: try the specified program, then fallback.
@@ -147,7 +652,7 @@ pub struct Command {
   command:     OsString,
   args:        Vec<OsString>,
   elevate:     Option<ElevationStrategy>,
-  ssh:         Option<String>,
+  ssh:         Option<SshTarget>,
   show_output: bool,
   env_vars:    HashMap<String, EnvAction>,
 }
@@ -180,7 +685,9 @@ impl Command {
     self
   }
 
-  /// Set whether to show command output.
+  /// Set whether the command is interactive. Interactive commands, such as
+  /// `nix repl`, use the terminal directly and their stderr is not kept for
+  /// error messages.
   #[must_use]
   pub fn show_output(mut self, show_output: bool) -> Self {
     self.show_output = show_output;
@@ -189,7 +696,7 @@ impl Command {
 
   /// Set the SSH target for remote command execution.
   #[must_use]
-  pub fn ssh(mut self, ssh: Option<String>) -> Self {
+  pub fn ssh(mut self, ssh: Option<SshTarget>) -> Self {
     self.ssh = ssh;
     self
   }
@@ -214,6 +721,16 @@ impl Command {
     self
   }
 
+  /// Set an environment variable for the command.
+  #[must_use]
+  pub fn env<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+    self.env_vars.insert(
+      key.as_ref().to_string(),
+      EnvAction::Set(value.as_ref().to_string()),
+    );
+    self
+  }
+
   /// Set a message to display before running the command.
   #[must_use]
   pub fn message<S: AsRef<str>>(mut self, message: S) -> Self {
@@ -278,10 +795,14 @@ impl Command {
       }
     }
 
-    // Preserve all variables in PRESERVE_ENV if present
+    // Preserve all variables in PRESERVE_ENV if present, unless they were
+    // set explicitly
     for &key in PRESERVE_ENV {
       if std::env::var(key).is_ok() {
-        self.env_vars.insert(key.to_string(), EnvAction::Preserve);
+        self
+          .env_vars
+          .entry(key.to_string())
+          .or_insert(EnvAction::Preserve);
       }
     }
 
@@ -311,80 +832,194 @@ impl Command {
     self
   }
 
+  /// Returns the variables to set for the command, sorted by name.
+  /// Preserved variables are only included with `preserve` and if they are
+  /// present in the current environment.
+  fn resolved_env(&self, preserve: bool) -> Vec<(String, String)> {
+    let mut env: Vec<_> = self
+      .env_vars
+      .iter()
+      .filter_map(|(key, action)| {
+        match action {
+          EnvAction::Set(value) => Some((key.clone(), value.clone())),
+          EnvAction::Preserve if preserve => {
+            std::env::var(key).ok().map(|value| (key.clone(), value))
+          },
+          // Removed variables are not passed on
+          _ => None,
+        }
+      })
+      .collect();
+    env.sort();
+    env
+  }
+
+  /// Returns the variables to set for the command on a remote host: the ones
+  /// set explicitly and the few preserved ones that the remote side needs.
+  /// The local `PATH`, `HOME` and `USER` would not match the remote host.
+  fn remote_env(&self) -> Vec<(String, String)> {
+    const LOCAL_ONLY: &[&str] = &["PATH", "HOME", "USER"];
+    const REMOTE_PRESERVE: &[&str] = &[
+      "NIXOS_INSTALL_BOOTLOADER",
+      "HOME_MANAGER_BACKUP_EXT",
+      "NIX_CONFIG",
+    ];
+
+    self
+      .resolved_env(true)
+      .into_iter()
+      .filter(|(key, _)| {
+        match self.env_vars.get(key) {
+          Some(EnvAction::Set(_)) => !LOCAL_ONLY.contains(&key.as_str()),
+          _ => REMOTE_PRESERVE.contains(&key.as_str()),
+        }
+      })
+      .collect()
+  }
+
   fn apply_env_to_exec(&self, mut cmd: Exec) -> Exec {
-    for (key, action) in &self.env_vars {
-      match action {
-        EnvAction::Set(value) => {
-          cmd = cmd.env(key, value);
-        },
-        EnvAction::Preserve => {
-          // Only preserve if present in current environment
-          if let Ok(value) = std::env::var(key) {
-            cmd = cmd.env(key, value);
-          }
-        },
-        EnvAction::Remove => {
-          // For remove, we'll handle this in the sudo construction
-          // by not including it in preserved variables
-        },
-      }
+    for (key, value) in self.resolved_env(true) {
+      cmd = cmd.env(key, value);
     }
     cmd
   }
 
-  /// Creates a Exec that contains elevates the program with proper environment
-  /// handling.
-  ///
-  /// Panics: If called when `self.elevate` is `None`
-  fn build_sudo_cmd(&self) -> Result<Exec> {
-    let elevation_program = self
+  /// Returns the command line that runs the command as root, locally or on
+  /// the remote host.
+  fn elevated_command_line(&self, remote: bool) -> Result<CommandLine> {
+    let strategy = self
       .elevate
       .as_ref()
-      .ok_or_else(|| eyre::eyre!("Command not found for elevation"))?
-      .resolve()
-      .context("Failed to resolve elevation program")?;
+      .ok_or_else(|| eyre::eyre!("Command not found for elevation"))?;
 
-    let mut cmd = Exec::cmd(&elevation_program);
-
-    // Use NH_SUDO_ASKPASS program for sudo if present
-    let program_name = elevation_program
-      .file_name()
-      .and_then(|name| name.to_str())
-      .ok_or_else(|| {
-        eyre::eyre!("Failed to determine elevation program name")
-      })?;
-    if program_name == "sudo" {
-      if let Ok(askpass) = std::env::var("NH_SUDO_ASKPASS") {
-        cmd = cmd.env("SUDO_ASKPASS", askpass).arg("-A");
-      }
+    let (elevation_program, env) = if remote {
+      (
+        PathBuf::from(remote_elevation_program(strategy)?),
+        self.remote_env(),
+      )
+    } else {
+      // NH_PRESERVE_ENV: set to "0" to disable preserving environment
+      // variables, "1" to force, unset defaults to force
+      let preserve_env = std::env::var("NH_PRESERVE_ENV")
+        .as_deref()
+        .map(|x| !matches!(x, "0"))
+        .unwrap_or(true);
+
+      (
+        strategy
+          .resolve()
+          .context("Failed to resolve elevation program")?,
+        self.resolved_env(preserve_env),
+      )
+    };
+
+    Ok(
+      ElevationProgram::from_path(&elevation_program)
+        .command_line(&elevation_program, env, &self.command, remote)?
+        .args(&self.args),
+    )
+  }
+
+  /// Returns the command line to run, with elevation applied but before it
+  /// is wrapped in ssh.
+  fn command_line(&self) -> Result<CommandLine> {
+    match (&self.elevate, &self.ssh) {
+      // Local elevation
+      (Some(_), None) => self.elevated_command_line(false),
+      // Remote elevation, sudo reads the password from stdin and the other
+      // programs must not ask for one
+      (Some(_), Some(_)) => self.elevated_command_line(true),
+      // No elevation
+      (None, _) => Ok(self.unelevated_command_line()),
     }
+  }
 
-    // NH_PRESERVE_ENV: set to "0" to disable preserving environment variables,
-    // "1" to force, unset defaults to force
-    let preserve_env = std::env::var("NH_PRESERVE_ENV")
-      .as_deref()
-      .map(|x| !matches!(x, "0"))
-      .unwrap_or(true);
-
-    // Insert 'env' command to explicitly pass environment variables to the
-    // elevated command
-    cmd = cmd.arg("env");
-    for arg in self.env_vars.iter().filter_map(|(key, action)| {
-      match action {
-        EnvAction::Set(value) => Some(format!("{key}={value}")),
-        EnvAction::Preserve if preserve_env => {
-          match std::env::var(key) {
-            Ok(value) => Some(format!("{key}={value}")),
-            Err(_) => None,
-          }
-        },
-        _ => None,
-      }
-    }) {
-      cmd = cmd.arg(arg);
+  /// Returns the password to feed to the remote elevation program, asking
+  /// for it or checking that no password is needed the first time a host is
+  /// used.
+  fn remote_auth(
+    &self,
+    host: &SshTarget,
+    strategy: &ElevationStrategy,
+  ) -> Result<Option<SecretString>> {
+    let program = remote_elevation_program(strategy)?;
+    let kind = ElevationProgram::from_path(Path::new(&program));
+
+    let _auth_lock = AUTH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    match get_cached_auth(&host.to_string(), &program) {
+      Some(RemoteAuth::Password(password)) => return Ok(Some(password)),
+      Some(RemoteAuth::Passwordless) => return Ok(None),
+      None => {},
+    }
+
+    if kind == ElevationProgram::Sudo {
+      let password =
+        inquire::Password::new(&format!("[sudo] password for {host}:"))
+          .without_confirmation()
+          .prompt()
+          .context("Failed to read sudo password")?;
+      let password = SecretString::new(password.into());
+      cache_auth(
+        &host.to_string(),
+        &program,
+        RemoteAuth::Password(password.clone()),
+      );
+      return Ok(Some(password));
+    }
+
+    // The other programs only prompt on a terminal, so they must be allowed
+    // to elevate without a password
+    debug!("Checking that {program} on {host} does not need a password");
+    let probe = CommandLine::new(&program)
+      .args(kind.non_interactive_args())
+      .arg("true");
+    let capture = ssh_wrap(&probe, Some(host))
+      .stdin(NullFile)
+      .stdout(Redirection::Pipe)
+      .stderr(Redirection::Pipe)
+      .capture()
+      .with_context(|| format!("Failed to run {program} on {host}"))?;
+
+    if !capture.success() {
+      bail!(
+        "{program} on {host} needs a password, which nh can only provide to \
+         sudo.\n{}\nAlternatively, pass `--elevation-program \
+         sudo`.\n\n{program} said: {}",
+        kind.passwordless_hint(),
+        capture.stderr_str().trim()
+      );
+    }
+
+    cache_auth(&host.to_string(), &program, RemoteAuth::Passwordless);
+    Ok(None)
+  }
+
+  fn unelevated_command_line(&self) -> CommandLine {
+    CommandLine {
+      env:  if self.ssh.is_some() {
+        self.remote_env()
+      } else {
+        self.resolved_env(true)
+      },
+      argv: std::iter::once(self.command.clone())
+        .chain(self.args.iter().cloned())
+        .collect(),
     }
+  }
 
-    Ok(cmd)
+  /// Renders the command as the shell command that will be executed,
+  /// including the ssh wrapping for remote commands.
+  fn to_shell(&self, line: &CommandLine) -> String {
+    match &self.ssh {
+      // Only the command line is passed to ssh, its environment is not
+      Some(host) => {
+        CommandLine::new("ssh")
+          .args(host.ssh_args())
+          .arg(line.to_remote_command())
+          .to_shell()
+      },
+      None => line.to_shell(),
+    }
   }
 
   /// Create a sudo command for self-elevation with proper environment handling
@@ -400,32 +1035,28 @@ impl Command {
     let current_exe = std::env::current_exe()
       .context("Failed to get current executable path")?;
 
+    Self::elevated_self(&current_exe, std::env::args_os().skip(1), strategy)
+  }
+
+  /// Builds the command that runs `exe` with `args` through the elevation
+  /// program, keeping every argument and environment value intact.
+  fn elevated_self<I>(
+    exe: &Path,
+    args: I,
+    strategy: ElevationStrategy,
+  ) -> Result<std::process::Command>
+  where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+  {
     // Self-elevation with proper environment handling
-    let cmd_builder = Self::new(&current_exe)
+    let line = Self::new(exe)
+      .args(args)
       .elevate(Some(strategy))
-      .with_required_env();
-
-    let sudo_exec = cmd_builder.build_sudo_cmd()?;
-
-    // Add the target executable and arguments to the sudo command
-    let exec_with_args = sudo_exec.arg(&current_exe);
-    let args: Vec<String> = std::env::args().skip(1).collect();
-    let final_exec = exec_with_args.args(&args);
-
-    // Convert Exec to std::process::Command by parsing the command line
-    let cmdline = final_exec.to_cmdline_lossy();
-    let parts: Vec<&str> = cmdline.split_whitespace().collect();
-
-    if parts.is_empty() {
-      bail!("Failed to build sudo command");
-    }
-
-    let mut std_cmd = std::process::Command::new(parts[0]);
-    if parts.len() > 1 {
-      std_cmd.args(&parts[1..]);
-    }
+      .with_required_env()
+      .elevated_command_line(false)?;
 
-    Ok(std_cmd)
+    Ok(line.to_command())
   }
 
   /// Run the configured command.
@@ -433,142 +1064,62 @@ impl Command {
   /// # Errors
   ///
   /// Returns an error if the command fails to execute or returns a non-zero
-  /// exit status.
-  ///
-  /// # Panics
-  ///
-  /// Panics if the command result is unexpectedly None.
+  /// exit status. The error includes the last lines the command wrote to
+  /// stderr.
   pub fn run(&self) -> Result<()> {
-    // Prompt for sudo password if needed for remote deployment
-    // FIXME: this implementation only covers Sudo. I *think* doas and run0 are
-    // able to read from stdin, but needs to be tested and possibly
-    // mitigated.
-    let sudo_password = if self.ssh.is_some() && self.elevate.is_some() {
-      let host = self.ssh.as_ref().unwrap();
-      if let Some(cached_password) = get_cached_password(host) {
-        Some(cached_password)
-      } else {
-        let password =
-          inquire::Password::new(&format!("[sudo] password for {}:", host))
-            .without_confirmation()
-            .prompt()
-            .context("Failed to read sudo password")?;
-        let secret_password = SecretString::new(password.into());
-        cache_password(host, secret_password.clone());
-        Some(secret_password)
-      }
-    } else {
-      None
-    };
-
-    let cmd = if self.elevate.is_some() && self.ssh.is_none() {
-      // Local elevation
-      self.build_sudo_cmd()?.arg(&self.command).args(&self.args)
-    } else if self.elevate.is_some() && self.ssh.is_some() {
-      // Build elevation command
-      let elevation_program = self
-        .elevate
-        .as_ref()
-        .unwrap()
-        .resolve()
-        .context("Failed to resolve elevation program")?;
-
-      let program_name = elevation_program
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or_else(|| {
-          eyre::eyre!("Failed to determine elevation program name")
-        })?;
-
-      let mut elev_cmd = Exec::cmd(&elevation_program);
-
-      // Add program-specific arguments
-      if program_name == "sudo" {
-        elev_cmd = elev_cmd.arg("--prompt=").arg("--stdin");
-      }
-
-      // Add env command to handle environment variables
-      elev_cmd = elev_cmd.arg("env");
-      for (key, action) in &self.env_vars {
-        match action {
-          EnvAction::Set(value) => {
-            elev_cmd = elev_cmd.arg(format!("{}={}", key, value));
-          },
-          EnvAction::Preserve => {
-            if let Ok(value) = std::env::var(key) {
-              elev_cmd = elev_cmd.arg(format!("{}={}", key, value));
-            }
-          },
-          _ => {},
-        }
-      }
-
-      elev_cmd.arg(&self.command).args(&self.args)
-    } else {
-      // No elevation
-      self.apply_env_to_exec(Exec::cmd(&self.command).args(&self.args))
-    };
-
-    // Configure output redirection based on show_output setting
-    let cmd = ssh_wrap(
-      if self.show_output {
-        cmd.stderr(Redirection::Merge)
-      } else {
-        cmd.stderr(Redirection::None).stdout(Redirection::None)
-      },
-      self.ssh.as_deref(),
-      sudo_password.as_ref(),
-    );
+    let line = self.command_line()?;
 
     if let Some(m) = &self.message {
       info!("{m}");
     }
 
-    debug!(?cmd);
-
+    // Print the exact command instead of running it, so that dry runs can be
+    // reviewed or replayed
     if self.dry {
+      println!("{}", self.to_shell(&line));
       return Ok(());
     }
 
+    let sudo_password = match (&self.ssh, &self.elevate) {
+      (Some(host), Some(strategy)) => self.remote_auth(host, strategy)?,
+      _ => None,
+    };
+
+    // Interactive commands get the terminal to themselves, everything else
+    // has its stderr kept to explain failures
+    let cmd = ssh_wrap(&line, self.ssh.as_ref());
+    debug!(?cmd);
+
     let msg = self
       .message
       .clone()
       .unwrap_or_else(|| "Command failed".to_string());
-    let res = cmd.capture();
-    match res {
-      Ok(capture) => {
-        let status = &capture.exit_status;
-        if !status.success() {
-          let stderr = capture.stderr_str();
-          if stderr.trim().is_empty() {
-            return Err(eyre::eyre!(format!(
-              "{} (exit status {:?})",
-              msg, status
-            )));
-          }
-          return Err(eyre::eyre!(format!(
-            "{} (exit status {:?})\nstderr:\n{}",
-            msg, status, stderr
-          )));
-        }
-        Ok(())
-      },
-      Err(e) => Err(e).wrap_err(msg),
+    let (status, stderr) =
+      run_streaming(cmd, sudo_password.as_ref(), !self.show_output)
+        .wrap_err_with(|| msg.clone())?;
+
+    if !status.success() {
+      if stderr.trim().is_empty() {
+        bail!("{msg} (exit status {status:?})");
+      }
+      bail!("{msg} (exit status {status:?})\nstderr:\n{stderr}");
     }
+    Ok(())
   }
 
-  /// Run the configured command and capture its output.
+  /// Run the configured command and capture its output. Commands for a
+  /// remote host run over ssh, without elevation.
   ///
   /// # Errors
   ///
   /// Returns an error if the command fails to execute.
   pub fn run_capture(&self) -> Result<Option<String>> {
-    let cmd = self.apply_env_to_exec(
-      Exec::cmd(&self.command)
-        .args(&self.args)
-        .stderr(Redirection::None)
-        .stdout(Redirection::Pipe),
-    );
+    let cmd = match &self.ssh {
+      Some(host) => ssh_wrap(&self.unelevated_command_line(), Some(host)),
+      None => self.apply_env_to_exec(Exec::cmd(&self.command).args(&self.args)),
+    }
+    .stderr(Redirection::None)
+    .stdout(Redirection::Pipe);
 
     if let Some(m) = &self.message {
       info!("{m}");
@@ -577,30 +1128,396 @@ impl Command {
     debug!(?cmd);
 
     if self.dry {
+      println!("{}", self.to_shell(&self.unelevated_command_line()));
       return Ok(None);
     }
     Ok(Some(cmd.capture()?.stdout_str()))
   }
-}
+}
+
+#[derive(Debug)]
+pub struct Build {
+  message:     Option<String>,
+  installable: Installable,
+  extra_args:  Vec<OsString>,
+  nom:         bool,
+  builder:     Option<SshTarget>,
+  copy:        CopyArgs,
+  out_link:    Option<PathBuf>,
+  keep_remote: bool,
+}
+
+impl Build {
+  #[must_use]
+  pub fn new(installable: Installable) -> Self {
+    Self {
+      message: None,
+      installable,
+      extra_args: vec![],
+      nom: false,
+      builder: None,
+      copy: CopyArgs::default(),
+      out_link: None,
+      keep_remote: false,
+    }
+  }
+
+  #[must_use]
+  pub fn message<S: AsRef<str>>(mut self, message: S) -> Self {
+    self.message = Some(message.as_ref().to_string());
+    self
+  }
+
+  #[must_use]
+  pub const fn nom(mut self, yes: bool) -> Self {
+    self.nom = yes;
+    self
+  }
+
+  /// Builds on `builder` over ssh instead of locally, like `nixos-rebuild
+  /// --build-host`: the configuration is evaluated here, its derivation is
+  /// copied to the builder and realised there, and the result is copied back
+  /// unless it is to be kept there with [`Build::keep_remote`].
+  #[must_use]
+  pub fn builder(mut self, builder: Option<SshTarget>) -> Self {
+    self.builder = builder;
+    self
+  }
+
+  /// Sets how the build is copied to and from the builder.
+  #[must_use]
+  pub fn copy_options(mut self, options: &CopyArgs) -> Self {
+    self.copy = options.clone();
+    self
+  }
+
+  /// Leaves the result on the builder rather than copying it back, for when
+  /// it is copied from there to another host. The out-link then points to a
+  /// store path that does not exist here.
+  #[must_use]
+  pub const fn keep_remote(mut self, yes: bool) -> Self {
+    self.keep_remote = yes;
+    self
+  }
+
+  #[must_use]
+  pub fn out_link<P: AsRef<Path>>(mut self, path: P) -> Self {
+    self.out_link = Some(path.as_ref().to_path_buf());
+    self
+  }
+
+  #[must_use]
+  pub fn extra_args<I>(mut self, args: I) -> Self
+  where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+  {
+    for elem in args {
+      self.extra_args.push(elem.as_ref().to_os_string());
+    }
+    self
+  }
+
+  #[must_use]
+  pub fn passthrough(self, passthrough: &NixBuildPassthroughArgs) -> Self {
+    self.extra_args(passthrough.generate_passthrough_args())
+  }
+
+  /// Run the build command.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the build command fails to execute.
+  pub fn run(&self) -> Result<()> {
+    if let Some(m) = &self.message {
+      info!("{m}");
+    }
+
+    match &self.builder {
+      Some(host) => self.run_remote(host),
+      None => self.run_local(),
+    }
+  }
+
+  fn out_link_args(&self) -> Vec<OsString> {
+    match &self.out_link {
+      Some(path) => vec!["--out-link".into(), path.clone().into_os_string()],
+      None => vec![],
+    }
+  }
+
+  fn run_local(&self) -> Result<()> {
+    let installable_args = self.installable.to_args();
+
+    let base_command = Exec::cmd("nix")
+      .arg("build")
+      .args(&installable_args)
+      .args(&self.out_link_args())
+      .args(&self.extra_args);
+
+    let exit = if self.nom {
+      let cmd = {
+        base_command
+          .args(&["--log-format", "internal-json", "--verbose"])
+          .stderr(Redirection::Merge)
+          .stdout(Redirection::Pipe)
+          | Exec::cmd("nom").args(&["--json"])
+      }
+      .stdout(Redirection::None);
+      debug!(?cmd);
+      interrupt::spawn(
+        || cmd.popen(),
+        |children| children.first().and_then(Popen::pid),
+      )
+      .and_then(|(mut children, _forward)| {
+        let mut last = ExitStatus::Undetermined;
+        for child in &mut children {
+          last = child.wait()?;
+        }
+        Ok(last)
+      })
+    } else {
+      let cmd = base_command
+        .stderr(Redirection::Merge)
+        .stdout(Redirection::None);
+
+      debug!(?cmd);
+      interrupt::spawn(|| cmd.popen(), Popen::pid)
+        .and_then(|(mut child, _forward)| Ok(child.wait()?))
+    };
+    interrupt::check()?;
+
+    match exit? {
+      ExitStatus::Exited(0) => (),
+      other => bail!(ExitError(other)),
+    }
+
+    Ok(())
+  }
+
+  fn run_remote(&self, host: &SshTarget) -> Result<()> {
+    let (eval_args, realise_args) = split_remote_build_args(&self.extra_args);
+
+    let drv = Command::new("nix")
+      .arg("path-info")
+      .arg("--derivation")
+      .args(self.installable.to_args())
+      .args(&eval_args)
+      .with_required_env()
+      .run_capture()
+      .wrap_err("Failed to evaluate the derivation")?
+      .map(|s| s.trim().to_string())
+      .filter(|s| !s.is_empty())
+      .ok_or_else(|| eyre::eyre!("Failed to evaluate the derivation"))?;
+    debug!(%drv, "Building on {host}");
+
+    Copy::to(host)
+      .derivation(true)
+      .path(&drv)
+      .options(&self.copy)
+      .message(format!("Copying the derivation to {host}"))
+      .run()?;
+
+    // The build log goes to stderr, only the output paths are captured
+    let realised = Command::new("nix-store")
+      .args(["--realise", &drv])
+      .args(&realise_args)
+      .ssh(Some(host.clone()))
+      .run_capture()
+      .wrap_err_with(|| format!("Failed to build on {host}"))?;
+    interrupt::check()?;
+    let Some(out) = realised
+      .as_deref()
+      .and_then(|out| out.lines().map(str::trim).find(|l| !l.is_empty()))
+    else {
+      bail!("Failed to build {drv} on {host}");
+    };
+
+    if self.keep_remote {
+      if let Some(out_link) = &self.out_link {
+        if out_link.symlink_metadata().is_ok() {
+          std::fs::remove_file(out_link).wrap_err_with(|| {
+            format!("Failed to replace {}", out_link.display())
+          })?;
+        }
+        std::os::unix::fs::symlink(out, out_link).wrap_err_with(|| {
+          format!("Failed to link {} to {out}", out_link.display())
+        })?;
+      }
+      return Ok(());
+    }
+
+    Copy::from(host)
+      .path(out)
+      .options(&self.copy)
+      .message(format!("Copying the result from {host}"))
+      .run()?;
+
+    if self.out_link.is_some() {
+      Command::new("nix")
+        .arg("build")
+        .arg(out)
+        .args(self.out_link_args())
+        .with_required_env()
+        .run()?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Build settings with a value, which `nix-store --realise` only takes as
+/// `--option`.
+const REMOTE_BUILD_SETTINGS: &[(&str, &str)] = &[
+  ("--max-jobs", "max-jobs"),
+  ("-j", "max-jobs"),
+  ("--cores", "cores"),
+  ("--builders", "builders"),
+];
+
+/// Build settings without a value, which `nix-store --realise` only takes as
+/// `--option`.
+const REMOTE_BUILD_SWITCHES: &[(&str, &str)] = &[
+  ("--keep-going", "keep-going"),
+  ("-k", "keep-going"),
+  ("--keep-failed", "keep-failed"),
+  ("-K", "keep-failed"),
+  ("--fallback", "fallback"),
+];
+
+/// Evaluation flags and the number of values they take.
+const EVAL_FLAGS_WITH_VALUES: &[(&str, usize)] = &[
+  ("-I", 1),
+  ("--include", 1),
+  ("--arg", 2),
+  ("--argstr", 2),
+  ("--override-input", 2),
+  ("--update-input", 1),
+  ("--reference-lock-file", 1),
+  ("--output-lock-file", 1),
+];
+
+/// Flags that only change what `nix build` prints or links, and the number of
+/// values they take.
+const OUTPUT_FLAGS: &[(&str, usize)] = &[
+  ("--log-format", 1),
+  ("--print-build-logs", 0),
+  ("-L", 0),
+  ("--no-build-output", 0),
+  ("-Q", 0),
+  ("--json", 0),
+  ("--no-link", 0),
+  ("--out-link", 1),
+  ("-o", 1),
+];
+
+/// Splits the arguments of a build on another host into those of the
+/// evaluation here and those of `nix-store --realise` on the builder. Build
+/// settings become `--option`, flags about the output are left out, and
+/// anything else is taken to be about the evaluation.
+fn split_remote_build_args(
+  args: &[OsString],
+) -> (Vec<OsString>, Vec<OsString>) {
+  let mut eval: Vec<OsString> = vec![];
+  let mut realise: Vec<OsString> = vec![];
+
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    let flag = arg.to_str().unwrap_or_default();
+    let takes = |flags: &[(&str, usize)]| {
+      flags.iter().find(|(f, _)| *f == flag).map(|(_, n)| *n)
+    };
+
+    if let Some((_, setting)) =
+      REMOTE_BUILD_SETTINGS.iter().find(|(f, _)| *f == flag)
+    {
+      if let Some(value) = args.next() {
+        realise.extend(["--option".into(), (*setting).into(), value.clone()]);
+      }
+    } else if let Some((_, setting)) =
+      REMOTE_BUILD_SWITCHES.iter().find(|(f, _)| *f == flag)
+    {
+      realise.extend(["--option", setting, "true"].map(OsString::from));
+    } else if flag == "--repair" {
+      realise.push(arg.clone());
+    } else if flag == "--option" {
+      // Settings such as substituters matter to both
+      let setting: Vec<OsString> = std::iter::once(arg)
+        .chain(args.by_ref().take(2))
+        .cloned()
+        .collect();
+      eval.extend(setting.iter().cloned());
+      realise.extend(setting);
+    } else if let Some(values) = takes(OUTPUT_FLAGS) {
+      debug!("Not passing {flag} to a build on another host");
+      args.by_ref().take(values).for_each(drop);
+    } else {
+      let values = takes(EVAL_FLAGS_WITH_VALUES).unwrap_or(0);
+      eval.extend(
+        std::iter::once(arg)
+          .chain(args.by_ref().take(values))
+          .cloned(),
+      );
+    }
+  }
+
+  (eval, realise)
+}
+
+#[derive(Debug, Error)]
+#[error("Command exited with status {0:?}")]
+pub struct ExitError(ExitStatus);
+
+/// Which way a [`Copy`] goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Direction {
+  To,
+  From,
+  /// From the given host to the host of the copy.
+  Between(SshTarget),
+}
+
+/// Copies store paths to or from another host with `nix copy`.
+#[derive(Debug)]
+pub struct Copy {
+  message:    Option<String>,
+  direction:  Direction,
+  host:       SshTarget,
+  paths:      Vec<OsString>,
+  derivation: bool,
+  options:    CopyArgs,
+  dry:        bool,
+}
+
+impl Copy {
+  /// Copies paths from here to `host`.
+  #[must_use]
+  pub fn to(host: &SshTarget) -> Self {
+    Self::new(Direction::To, host)
+  }
 
-#[derive(Debug)]
-pub struct Build {
-  message:     Option<String>,
-  installable: Installable,
-  extra_args:  Vec<OsString>,
-  nom:         bool,
-  builder:     Option<String>,
-}
+  /// Copies paths from `host` to here.
+  #[must_use]
+  pub fn from(host: &SshTarget) -> Self {
+    Self::new(Direction::From, host)
+  }
 
-impl Build {
+  /// Copies paths from `source` to `host` without storing them here. Both
+  /// hosts get the ssh options of `host`.
   #[must_use]
-  pub const fn new(installable: Installable) -> Self {
+  pub fn between(source: &SshTarget, host: &SshTarget) -> Self {
+    Self::new(Direction::Between(source.clone()), host)
+  }
+
+  fn new(direction: Direction, host: &SshTarget) -> Self {
     Self {
       message: None,
-      installable,
-      extra_args: vec![],
-      nom: false,
-      builder: None,
+      direction,
+      host: host.clone(),
+      paths: vec![],
+      derivation: false,
+      options: CopyArgs::default(),
+      dry: false,
     }
   }
 
@@ -611,100 +1528,235 @@ impl Build {
   }
 
   #[must_use]
-  pub fn extra_arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
-    self.extra_args.push(arg.as_ref().to_os_string());
+  pub fn path<P: AsRef<OsStr>>(mut self, path: P) -> Self {
+    self.paths.push(path.as_ref().to_os_string());
     self
   }
 
+  /// Copies the derivations themselves rather than their outputs.
   #[must_use]
-  pub const fn nom(mut self, yes: bool) -> Self {
-    self.nom = yes;
+  pub const fn derivation(mut self, yes: bool) -> Self {
+    self.derivation = yes;
     self
   }
 
   #[must_use]
-  pub fn builder(mut self, builder: Option<String>) -> Self {
-    self.builder = builder;
+  pub fn options(mut self, options: &CopyArgs) -> Self {
+    self.options = options.clone();
     self
   }
 
   #[must_use]
-  pub fn extra_args<I>(mut self, args: I) -> Self
-  where
-    I: IntoIterator,
-    I::Item: AsRef<OsStr>,
-  {
-    for elem in args {
-      self.extra_args.push(elem.as_ref().to_os_string());
-    }
+  pub const fn dry(mut self, dry: bool) -> Self {
+    self.dry = dry;
     self
   }
 
-  #[must_use]
-  pub fn passthrough(self, passthrough: &NixBuildPassthroughArgs) -> Self {
-    self.extra_args(passthrough.generate_passthrough_args())
+  /// Returns the store URI of `host`, with the store options.
+  fn store_uri(&self, host: &SshTarget) -> String {
+    let options: Vec<&str> = self
+      .options
+      .compress
+      .then_some("compress=true")
+      .into_iter()
+      .chain(self.options.store_options.iter().map(String::as_str))
+      .collect();
+
+    match options.as_slice() {
+      [] => host.store_uri(),
+      _ => format!("{}?{}", host.store_uri(), options.join("&")),
+    }
   }
 
-  /// Run the build command.
+  fn command(&self) -> Command {
+    let stores = match &self.direction {
+      Direction::To => vec!["--to".to_string(), self.store_uri(&self.host)],
+      Direction::From => vec!["--from".to_string(), self.store_uri(&self.host)],
+      Direction::Between(source) => {
+        vec![
+          "--from".to_string(),
+          self.store_uri(source),
+          "--to".to_string(),
+          self.store_uri(&self.host),
+        ]
+      },
+    };
+
+    let mut command = Command::new("nix").arg("copy");
+    if self.derivation {
+      command = command.arg("--derivation");
+    }
+    if self.options.use_substitutes && self.direction != Direction::From {
+      command = command.arg("--substitute-on-destination");
+    }
+    command
+      .args(stores)
+      .args(&self.paths)
+      .env("NIX_SSHOPTS", self.host.nix_sshopts())
+      .with_required_env()
+      .dry(self.dry)
+  }
+
+  /// Run the copy. Copies to another host report how much they sent.
   ///
   /// # Errors
   ///
-  /// Returns an error if the build command fails to execute.
+  /// Returns an error if `nix copy` fails.
   pub fn run(&self) -> Result<()> {
-    if let Some(m) = &self.message {
-      info!("{m}");
-    }
+    let command = match &self.message {
+      Some(message) => self.command().message(message),
+      None => self.command(),
+    };
 
-    let installable_args = self.installable.to_args();
+    // The size is only known before copying, when the paths are missing
+    let missing = if self.direction == Direction::To && !self.dry {
+      self
+        .missing_paths()
+        .inspect_err(|err| warn!("Not reporting the copied size: {err}"))
+        .ok()
+    } else {
+      None
+    };
 
-    let base_command = Exec::cmd("nix")
-      .arg("build")
-      .args(&installable_args)
-      .args(&match &self.builder {
-        Some(host) => {
-          vec!["--builders".to_string(), format!("ssh://{host} - - - 100")]
+    command.run()?;
+
+    if let Some((count, bytes)) = missing {
+      let host = &self.host;
+      let paths = if count == 1 { "path" } else { "paths" };
+      let size = format_bytes(bytes);
+      match (count, self.options.use_substitutes) {
+        (0, _) => info!("{host} already had every path"),
+        (_, false) => info!("Copied {count} {paths} ({size}) to {host}"),
+        (_, true) => {
+          info!("Copied or substituted {count} {paths} ({size}) on {host}");
         },
-        None => vec![],
-      })
-      .args(&self.extra_args);
-
-    let exit = if self.nom {
-      let cmd = {
-        base_command
-          .args(&["--log-format", "internal-json", "--verbose"])
-          .stderr(Redirection::Merge)
-          .stdout(Redirection::Pipe)
-          | Exec::cmd("nom").args(&["--json"])
       }
-      .stdout(Redirection::None);
-      debug!(?cmd);
-      cmd.join()
-    } else {
-      let cmd = base_command
-        .stderr(Redirection::Merge)
-        .stdout(Redirection::None);
-
-      debug!(?cmd);
-      cmd.join()
-    };
+    }
+    Ok(())
+  }
 
-    match exit? {
-      ExitStatus::Exited(0) => (),
-      other => bail!(ExitError(other)),
+  /// Returns the number and total size of the paths of the closure that the
+  /// host does not have yet.
+  fn missing_paths(&self) -> Result<(usize, u64)> {
+    let mut path_info = Command::new("nix")
+      .args(["path-info", "--json", "--recursive"])
+      .with_required_env();
+    if self.derivation {
+      path_info = path_info.arg("--derivation");
+    }
+    let json = path_info
+      .args(&self.paths)
+      .run_capture()?
+      .unwrap_or_default();
+    let sizes = nar_sizes(&json)?;
+
+    // A closure is too long for a single command line
+    let mut invalid = String::new();
+    for batch in batches(sizes.keys(), MAX_REMOTE_ARGS) {
+      invalid += &Command::new("nix-store")
+        .args(["--check-validity", "--print-invalid"])
+        .args(batch)
+        .ssh(Some(self.host.clone()))
+        .run_capture()
+        .wrap_err_with(|| {
+          format!("Failed to check which paths {} is missing", self.host)
+        })?
+        .unwrap_or_default();
     }
 
-    Ok(())
+    let missing: Vec<&str> = invalid
+      .lines()
+      .filter(|path| sizes.contains_key(*path))
+      .collect();
+    let bytes = missing.iter().map(|path| sizes[*path]).sum();
+    Ok((missing.len(), bytes))
   }
 }
 
-#[derive(Debug, Error)]
-#[error("Command exited with status {0:?}")]
-pub struct ExitError(ExitStatus);
+/// Longest list of paths passed to one remote command, well below the 128 KiB
+/// Linux allows for the single argument that the remote shell gets.
+const MAX_REMOTE_ARGS: usize = 64 * 1024;
+
+/// Splits `paths` into batches whose total length, counting a separator for
+/// each, stays within `max` bytes. A path longer than `max` gets a batch of
+/// its own.
+fn batches<'a>(
+  paths: impl IntoIterator<Item = &'a String>,
+  max: usize,
+) -> Vec<Vec<&'a String>> {
+  let mut batches = Vec::new();
+  let mut batch = Vec::new();
+  let mut len = 0;
+  for path in paths {
+    if !batch.is_empty() && len + path.len() + 1 > max {
+      batches.push(std::mem::take(&mut batch));
+      len = 0;
+    }
+    len += path.len() + 1;
+    batch.push(path);
+  }
+  if !batch.is_empty() {
+    batches.push(batch);
+  }
+  batches
+}
+
+/// Reads the NAR size of every path from the output of `nix path-info
+/// --json`, which is a list of objects before Nix 2.19 and a map by path
+/// since.
+fn nar_sizes(json: &str) -> Result<HashMap<String, u64>> {
+  let value: serde_json::Value =
+    serde_json::from_str(json).context("Invalid output of nix path-info")?;
+  let size = |info: &serde_json::Value| {
+    info
+      .get("narSize")
+      .and_then(serde_json::Value::as_u64)
+      .unwrap_or_default()
+  };
+
+  let sizes = match value {
+    serde_json::Value::Array(infos) => {
+      infos
+        .iter()
+        .filter_map(|info| {
+          let path = info.get("path")?.as_str()?;
+          Some((path.to_string(), size(info)))
+        })
+        .collect()
+    },
+    serde_json::Value::Object(infos) => {
+      infos
+        .iter()
+        .map(|(path, info)| (path.clone(), size(info)))
+        .collect()
+    },
+    _ => bail!("Unexpected output of nix path-info: {json}"),
+  };
+  Ok(sizes)
+}
+
+/// Formats a size in bytes with a binary unit.
+fn format_bytes(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+  #[expect(clippy::cast_precision_loss)]
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < UNITS.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  match unit {
+    0 => format!("{bytes} B"),
+    _ => format!("{size:.1} {}", UNITS[unit]),
+  }
+}
 
 #[cfg(test)]
 mod tests {
   use std::{env, ffi::OsString};
 
+  use proptest::prelude::*;
   use serial_test::serial;
 
   use super::*;
@@ -775,7 +1827,7 @@ mod tests {
       .dry(true)
       .show_output(true)
       .elevate(Some(ElevationStrategy::Force("sudo")))
-      .ssh(Some("host".to_string()))
+      .ssh(Some("host".parse().unwrap()))
       .message("test message")
       .arg("arg1")
       .args(["arg2", "arg3"]);
@@ -783,7 +1835,7 @@ mod tests {
     assert!(cmd.dry);
     assert!(cmd.show_output);
     assert_eq!(cmd.elevate, Some(ElevationStrategy::Force("sudo")));
-    assert_eq!(cmd.ssh, Some("host".to_string()));
+    assert_eq!(cmd.ssh, Some("host".parse().unwrap()));
     assert_eq!(cmd.message, Some("test message".to_string()));
     assert_eq!(cmd.args, vec![
       OsString::from("arg1"),
@@ -959,7 +2011,7 @@ mod tests {
   fn test_build_sudo_cmd_basic() {
     let cmd =
       Command::new("test").elevate(Some(ElevationStrategy::Force("sudo")));
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
 
     // Platform-agnostic: 'sudo' may not be the first token if env vars are
     // injected (e.g., NH_SUDO_ASKPASS). Accept any command line where
@@ -979,7 +2031,7 @@ mod tests {
       .preserve_envs(["VAR1", "VAR2"])
       .elevate(Some(ElevationStrategy::Force("sudo")));
 
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     assert!(cmdline.contains("env"));
@@ -998,7 +2050,7 @@ mod tests {
       .preserve_envs(["VAR1", "VAR2"])
       .elevate(Some(ElevationStrategy::Force("sudo")));
 
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     assert!(cmdline.contains("env"));
@@ -1016,7 +2068,7 @@ mod tests {
       EnvAction::Set("test_value".to_string()),
     );
 
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     // Should contain env command with variable
@@ -1039,7 +2091,7 @@ mod tests {
       .env_vars
       .insert("VAR_TO_REMOVE".to_string(), EnvAction::Remove);
 
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     assert!(cmdline.contains("env"));
@@ -1054,7 +2106,7 @@ mod tests {
 
     let cmd =
       Command::new("test").elevate(Some(ElevationStrategy::Force("sudo")));
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     // Should contain -A flag for askpass
@@ -1080,7 +2132,7 @@ mod tests {
       .env_vars
       .insert("PRESERVE_VAR".to_string(), EnvAction::Preserve);
 
-    let sudo_exec = cmd.build_sudo_cmd().unwrap();
+    let sudo_exec = cmd.elevated_command_line(false).unwrap().to_exec();
     let cmdline = sudo_exec.to_cmdline_lossy();
 
     // Count occurrences of "env" in the command line
@@ -1102,10 +2154,91 @@ mod tests {
     assert!(cmdline.contains("PRESERVE_VAR=preserve"));
   }
 
+  #[test]
+  #[serial]
+  fn test_build_sudo_cmd_run0_uses_setenv() {
+    let mut cmd =
+      Command::new("test").elevate(Some(ElevationStrategy::Force("run0")));
+    cmd.env_vars.insert(
+      "TEST_VAR".to_string(),
+      EnvAction::Set("test value".to_string()),
+    );
+
+    let cmdline = cmd
+      .elevated_command_line(false)
+      .unwrap()
+      .to_exec()
+      .to_cmdline_lossy();
+
+    assert!(cmdline.starts_with("run0 "));
+    assert!(cmdline.contains("'--setenv=TEST_VAR=test value'"));
+    assert!(!cmdline.split_whitespace().any(|tok| tok == "env"));
+    assert!(cmdline.ends_with(" test"));
+  }
+
+  #[test]
+  #[serial]
+  fn test_build_sudo_cmd_pkexec_uses_absolute_paths() {
+    let mut cmd =
+      Command::new("sh").elevate(Some(ElevationStrategy::Force("pkexec")));
+    cmd.env_vars.insert(
+      "TEST_VAR".to_string(),
+      EnvAction::Set("test_value".to_string()),
+    );
+
+    let line = cmd.elevated_command_line(false).unwrap();
+    let argv: Vec<_> = line.argv.iter().map(PathBuf::from).collect();
+
+    assert_eq!(argv[0], PathBuf::from("pkexec"));
+    assert!(argv[1].is_absolute() && argv[1].ends_with("env"));
+    assert!(argv.contains(&PathBuf::from("TEST_VAR=test_value")));
+    let command = argv.last().unwrap();
+    assert!(command.is_absolute() && command.ends_with("sh"));
+  }
+
+  #[test]
+  #[serial]
+  fn test_build_sudo_cmd_doas_passes_env() {
+    let mut cmd =
+      Command::new("test").elevate(Some(ElevationStrategy::Force("doas")));
+    cmd.env_vars.insert(
+      "TEST_VAR".to_string(),
+      EnvAction::Set("test_value".to_string()),
+    );
+
+    let cmdline = cmd
+      .elevated_command_line(false)
+      .unwrap()
+      .to_exec()
+      .to_cmdline_lossy();
+
+    assert!(cmdline.starts_with("doas env "));
+    assert!(cmdline.contains("TEST_VAR=test_value"));
+  }
+
+  #[test]
+  #[serial]
+  fn test_build_sudo_cmd_askpass_only_for_sudo() {
+    let _guard = EnvGuard::new("NH_SUDO_ASKPASS", "/path/to/askpass");
+
+    for program in ["doas", "run0"] {
+      let cmd =
+        Command::new("test").elevate(Some(ElevationStrategy::Force(program)));
+      let cmdline = cmd
+        .elevated_command_line(false)
+        .unwrap()
+        .to_exec()
+        .to_cmdline_lossy();
+
+      assert!(!cmdline.contains("-A"), "{cmdline}");
+      assert!(!cmdline.contains("SUDO_ASKPASS"), "{cmdline}");
+    }
+  }
+
   #[test]
   fn test_build_new() {
     let installable = Installable::Flake {
-      reference: "github:user/repo".to_string(),
+      reference: "github:user/repo".parse().unwrap(),
       attribute: vec!["package".to_string()],
     };
 
@@ -1121,16 +2254,16 @@ mod tests {
   #[test]
   fn test_build_builder_pattern() {
     let installable = Installable::Flake {
-      reference: "github:user/repo".to_string(),
+      reference: "github:user/repo".parse().unwrap(),
       attribute: vec!["package".to_string()],
     };
 
     let build = Build::new(installable)
       .message("Building package")
-      .extra_arg("--verbose")
-      .extra_args(["--option", "setting", "value"])
+      .extra_args(["--verbose", "--option", "setting", "value"])
       .nom(true)
-      .builder(Some("build-host".to_string()));
+      .builder(Some("build-host".parse().unwrap()))
+      .out_link("/tmp/result");
 
     assert_eq!(build.message, Some("Building package".to_string()));
     assert_eq!(build.extra_args, vec![
@@ -1140,13 +2273,371 @@ mod tests {
       OsString::from("value")
     ]);
     assert!(build.nom);
-    assert_eq!(build.builder, Some("build-host".to_string()));
+    assert_eq!(build.builder, Some("build-host".parse().unwrap()));
+    assert_eq!(build.out_link_args(), ["--out-link", "/tmp/result"]);
+  }
+
+  #[test]
+  fn test_copy_store_uri() {
+    let host: SshTarget = "ssh-ng://root@host".parse().unwrap();
+    assert_eq!(Copy::to(&host).store_uri(&host), "ssh-ng://root@host");
+
+    let options = CopyArgs {
+      use_substitutes: true,
+      compress:        true,
+      store_options:   vec!["remote-program=nix-daemon".to_string()],
+    };
+    let copy = Copy::to(&host).path("/nix/store/a").options(&options);
+    assert_eq!(
+      copy.store_uri(&host),
+      "ssh-ng://root@host?compress=true&remote-program=nix-daemon"
+    );
+    assert!(
+      copy
+        .command()
+        .args
+        .contains(&OsString::from("--substitute-on-destination"))
+    );
+
+    // Only the destination can substitute
+    let copy = Copy::from(&host).options(&options);
+    assert!(
+      !copy
+        .command()
+        .args
+        .contains(&OsString::from("--substitute-on-destination"))
+    );
+
+    // Straight from one host to another, without storing the paths here
+    let builder: SshTarget = "builder".parse().unwrap();
+    let copy = Copy::between(&builder, &host).path("/nix/store/a");
+    assert_eq!(copy.command().args, [
+      "copy",
+      "--from",
+      "ssh://builder",
+      "--to",
+      "ssh-ng://root@host",
+      "/nix/store/a"
+    ]);
+  }
+
+  #[test]
+  fn test_split_remote_build_args() {
+    let args: Vec<OsString> = [
+      "--max-jobs",
+      "4",
+      "--keep-going",
+      "--impure",
+      "-I",
+      "nixpkgs=/src",
+      "--override-input",
+      "nixpkgs",
+      "github:NixOS/nixpkgs",
+      "--log-format",
+      "bar",
+      "--print-build-logs",
+      "--option",
+      "substituters",
+      "https://cache",
+      "--repair",
+    ]
+    .map(OsString::from)
+    .to_vec();
+
+    let (eval, realise) = split_remote_build_args(&args);
+    assert_eq!(eval, [
+      "--impure",
+      "-I",
+      "nixpkgs=/src",
+      "--override-input",
+      "nixpkgs",
+      "github:NixOS/nixpkgs",
+      "--option",
+      "substituters",
+      "https://cache",
+    ]);
+    assert_eq!(realise, [
+      "--option",
+      "max-jobs",
+      "4",
+      "--option",
+      "keep-going",
+      "true",
+      "--option",
+      "substituters",
+      "https://cache",
+      "--repair",
+    ]);
+  }
+
+  #[test]
+  fn test_nar_sizes() {
+    // Nix 2.19 and later
+    let sizes = nar_sizes(
+      r#"{"/nix/store/a": {"narSize": 100}, "/nix/store/b": {"narSize": 20}}"#,
+    )
+    .unwrap();
+    assert_eq!(sizes["/nix/store/a"], 100);
+    assert_eq!(sizes["/nix/store/b"], 20);
+
+    // Earlier versions
+    let sizes =
+      nar_sizes(r#"[{"path": "/nix/store/a", "narSize": 100}]"#).unwrap();
+    assert_eq!(sizes["/nix/store/a"], 100);
+
+    assert!(nar_sizes("error: path is not valid").is_err());
+  }
+
+  #[test]
+  fn test_batches() {
+    let paths: Vec<String> =
+      (0..10).map(|i| format!("/nix/store/{i}-pkg")).collect();
+
+    // Every path is 16 bytes with its separator
+    let split = batches(&paths, 40);
+    assert_eq!(split.iter().map(Vec::len).collect::<Vec<_>>(), [
+      2, 2, 2, 2, 2
+    ]);
+    assert_eq!(split.concat(), paths.iter().collect::<Vec<_>>());
+
+    assert_eq!(batches(&paths, 4).len(), 10);
+    assert!(batches(&[], 40).is_empty());
+  }
+
+  #[test]
+  fn test_format_bytes() {
+    assert_eq!(format_bytes(512), "512 B");
+    assert_eq!(format_bytes(1536), "1.5 KiB");
+    assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+  }
+
+  #[test]
+  fn test_shell_quote() {
+    assert_eq!(shell_quote("nix"), "nix");
+    assert_eq!(shell_quote("ssh://user@host:22"), "ssh://user@host:22");
+    assert_eq!(shell_quote("KEY=value"), "KEY=value");
+    assert_eq!(shell_quote(""), "''");
+    assert_eq!(shell_quote("two words"), "'two words'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    assert_eq!(shell_quote("$HOME"), "'$HOME'");
+  }
+
+  #[test]
+  fn test_command_line_to_shell() {
+    let mut line = CommandLine::new("nix")
+      .args(["build", "--profile", "/nix/var/nix/profiles/system"])
+      .arg(".#nixosConfigurations.\"my host\"");
+    line
+      .env
+      .push(("NIX_SSHOPTS".to_string(), "-p 2222".to_string()));
+
+    assert_eq!(
+      line.to_shell(),
+      "NIX_SSHOPTS='-p 2222' nix build --profile /nix/var/nix/profiles/system \
+       '.#nixosConfigurations.\"my host\"'"
+    );
+
+    // A program containing `=` must not be mistaken for an assignment
+    assert_eq!(CommandLine::new("a=b").arg("c").to_shell(), "'a=b' c");
+  }
+
+  #[test]
+  #[serial]
+  fn test_command_to_shell_includes_env_and_elevation() {
+    let _guard = EnvGuard::new("NH_PRESERVE_ENV", "1");
+
+    let mut cmd = Command::new("switch-to-configuration")
+      .arg("test")
+      .elevate(Some(ElevationStrategy::Force("sudo")));
+    cmd.env_vars.insert(
+      "NH_TEST_VAR".to_string(),
+      EnvAction::Set("a value".to_string()),
+    );
+
+    let shell = cmd.to_shell(&cmd.command_line().unwrap());
+    assert!(
+      shell.ends_with("env 'NH_TEST_VAR=a value' switch-to-configuration test"),
+      "unexpected command: {shell}"
+    );
+    assert!(shell.split_whitespace().any(|word| word.ends_with("sudo")));
   }
 
   #[test]
+  #[serial]
+  fn test_command_to_shell_over_ssh() {
+    let cmd = Command::new("echo")
+      .arg("two words")
+      .ssh(Some("user@host".parse().unwrap()));
+
+    assert_eq!(
+      cmd.to_shell(&cmd.command_line().unwrap()),
+      r"ssh -T user@host 'echo '\''two words'\'''"
+    );
+  }
+
+  #[test]
+  #[serial]
+  fn test_remote_env_leaves_out_local_paths() {
+    let _path = EnvGuard::new("PATH", "/home/me/.nix-profile/bin:/usr/bin");
+    let _config = EnvGuard::new("NIX_CONFIG", "max-jobs = 4");
+    let _nix_path = EnvGuard::new("NIX_PATH", "nixpkgs=/home/me/nixpkgs");
+
+    for elevate in [None, Some(ElevationStrategy::Force("sudo"))] {
+      let line = Command::new("nix")
+        .elevate(elevate)
+        .ssh(Some("host".parse().unwrap()))
+        .env("HOME_MANAGER_BACKUP_EXT", "bak")
+        .with_required_env()
+        .command_line()
+        .unwrap();
+      let remote = line.to_remote_command().to_string_lossy().into_owned();
+
+      for local in ["PATH=", "HOME=", "USER=", "NIX_PATH="] {
+        assert!(!remote.contains(local), "{remote}");
+      }
+      assert!(
+        remote.contains("NIX_CONFIG=") && remote.contains("max-jobs = 4")
+      );
+      assert!(remote.contains("HOME_MANAGER_BACKUP_EXT=bak"), "{remote}");
+    }
+  }
+
+  #[test]
+  fn test_remote_pkexec_sets_path() {
+    let line = Command::new("nix")
+      .arg("build")
+      .elevate(Some(ElevationStrategy::Force("pkexec")))
+      .ssh(Some("host".parse().unwrap()))
+      .env("NIXOS_INSTALL_BOOTLOADER", "1")
+      .command_line()
+      .unwrap();
+    let argv: Vec<_> = line
+      .argv
+      .iter()
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect();
+
+    assert_eq!(argv[..4], [
+      "pkexec",
+      "--disable-internal-agent",
+      "/usr/bin/env",
+      &format!("PATH={REMOTE_SYSTEM_PATH}"),
+    ]);
+    assert!(REMOTE_SYSTEM_PATH.contains(":/run/current-system/sw/bin:"));
+    assert_eq!(argv[4..], ["NIXOS_INSTALL_BOOTLOADER=1", "nix", "build"]);
+  }
+
+  #[test]
+  fn test_remote_elevation_never_prompts() {
+    for (program, flags) in [
+      ("sudo", "--prompt= --stdin"),
+      ("doas", "-n"),
+      ("run0", "--no-ask-password"),
+      ("pkexec", "--disable-internal-agent"),
+    ] {
+      let line = Command::new("switch-to-configuration")
+        .arg("boot")
+        .elevate(Some(ElevationStrategy::Force(program)))
+        .ssh(Some("host".parse().unwrap()))
+        .command_line()
+        .unwrap();
+      let argv: Vec<_> = line
+        .argv
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+      assert_eq!(argv[0], program);
+      assert_eq!(argv[1..=flags.split(' ').count()].join(" "), flags);
+      assert_eq!(argv[argv.len() - 2..], ["switch-to-configuration", "boot"]);
+    }
+  }
+
+  #[test]
+  fn test_remote_auth_cache_is_per_program() {
+    cache_auth("cache-test", "doas", RemoteAuth::Passwordless);
+    assert!(matches!(
+      get_cached_auth("cache-test", "doas"),
+      Some(RemoteAuth::Passwordless)
+    ));
+    assert!(get_cached_auth("cache-test", "sudo").is_none());
+  }
+
+  #[test]
+  fn test_sudo_keepalive_only_for_sudo() {
+    let keepalive =
+      SudoKeepAlive::start(&ElevationStrategy::Force("doas")).unwrap();
+    assert!(keepalive.is_none());
+  }
+
+  #[test]
+  #[serial]
+  fn test_sudo_keepalive_stops_on_drop() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let sudo = dir.path().join("sudo");
+    let log = dir.path().join("log");
+    std::fs::write(
+      &sudo,
+      format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&sudo, std::fs::Permissions::from_mode(0o755))
+      .unwrap();
+
+    let keepalive = SudoKeepAlive::start(&ElevationStrategy::Prefer(sudo))
+      .unwrap()
+      .unwrap();
+    let started = std::time::Instant::now();
+    drop(keepalive);
+
+    assert!(started.elapsed() < SUDO_REFRESH_INTERVAL);
+    assert_eq!(std::fs::read_to_string(log).unwrap(), "-v\n");
+  }
+
+  #[test]
+  #[serial]
+  fn test_self_elevation_keeps_arguments_intact() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let _guard = EnvGuard::new("NH_SUDO_ASKPASS", "/path/with space/askpass");
+
+    let args: Vec<&OsStr> = vec![
+      OsStr::new("clean"),
+      OsStr::new("all"),
+      OsStr::new("--keep-since"),
+      OsStr::new("2 weeks"),
+      OsStr::new(""),
+      OsStr::new("it's \"quoted\"\ttab\nnewline"),
+      OsStr::new("$HOME `id` *"),
+      OsStr::from_bytes(b"not \xff utf-8"),
+    ];
+    let exe = Path::new("/nix/store/some path/bin/nh");
+
+    let command =
+      Command::elevated_self(exe, &args, ElevationStrategy::Force("sudo"))
+        .unwrap();
+
+    assert_eq!(command.get_program(), "sudo");
+    let argv: Vec<&OsStr> = command.get_args().collect();
+    let exe_at = argv.iter().position(|arg| *arg == exe).unwrap();
+    assert_eq!(argv[..2], [OsStr::new("-A"), OsStr::new("env")]);
+    assert_eq!(argv[exe_at + 1..], args[..]);
+
+    let askpass = command
+      .get_envs()
+      .find(|(key, _)| *key == "SUDO_ASKPASS")
+      .and_then(|(_, value)| value);
+    assert_eq!(askpass, Some(OsStr::new("/path/with space/askpass")));
+  }
+
+  #[test]
+  #[serial]
   fn test_ssh_wrap_with_ssh() {
-    let cmd = subprocess::Exec::cmd("echo").arg("hello");
-    let wrapped = ssh_wrap(cmd, Some("user@host"), None);
+    let line = CommandLine::new("echo").arg("hello");
+    let target: SshTarget = "user@host".parse().unwrap();
+    let wrapped = ssh_wrap(&line, Some(&target));
 
     let cmdline = wrapped.to_cmdline_lossy();
     assert!(cmdline.starts_with("ssh"));
@@ -1156,23 +2647,61 @@ mod tests {
 
   #[test]
   fn test_ssh_wrap_without_ssh() {
-    let cmd = subprocess::Exec::cmd("echo").arg("hello");
-    let wrapped = ssh_wrap(cmd.clone(), None, None);
+    let line = CommandLine::new("echo").arg("hello");
+    let wrapped = ssh_wrap(&line, None);
 
     // Should return the original command unchanged
-    assert_eq!(wrapped.to_cmdline_lossy(), cmd.to_cmdline_lossy());
+    assert_eq!(
+      wrapped.to_cmdline_lossy(),
+      line.to_exec().to_cmdline_lossy()
+    );
   }
 
   #[test]
-  fn test_ssh_wrap_with_password() {
-    let cmd = subprocess::Exec::cmd("echo").arg("hello");
-    let password = SecretString::new("testpass".into());
-    let wrapped = ssh_wrap(cmd, Some("user@host"), Some(&password));
+  fn test_ignore_sigint() {
+    let output = subprocess::Exec::cmd(interrupt::IGNORE_SIGINT[0])
+      .args(&interrupt::IGNORE_SIGINT[1..])
+      .args(&["sh", "-c", "kill -INT $$; echo alive"])
+      .stdout(Redirection::Pipe)
+      .capture()
+      .unwrap();
+    assert_eq!(output.stdout_str(), "alive\n");
+  }
 
-    let cmdline = wrapped.to_cmdline_lossy();
-    assert!(cmdline.starts_with("ssh"));
-    assert!(cmdline.contains("-T"));
-    assert!(cmdline.contains("user@host"));
+  #[test]
+  fn test_output_tail_is_bounded() {
+    let mut tail = OutputTail::new(2);
+    tail.push(b"one\ntwo\nthr");
+    tail.push(b"ee\ndownloading 10%\rdownloading 100%");
+    assert_eq!(tail.into_string(), "three\ndownloading 100%");
+
+    let mut tail = OutputTail::new(2);
+    tail.push(&[b'x'; MAX_TAIL_LINE * 2]);
+    assert_eq!(tail.into_string().len(), MAX_TAIL_LINE);
+  }
+
+  #[test]
+  fn test_run_streaming_keeps_stderr_tail() {
+    let password = SecretString::new("hunter2".into());
+    let cmd = subprocess::Exec::cmd("sh")
+      .arg("-c")
+      .arg("read pw; echo \"got $pw\" >&2; exit 3");
+
+    let (status, stderr) = run_streaming(cmd, Some(&password), true).unwrap();
+    assert_eq!(status, ExitStatus::Exited(3));
+    assert_eq!(stderr, "got hunter2");
+  }
+
+  #[test]
+  fn test_run_reports_stderr_tail() {
+    let err = Command::new("sh")
+      .args(["-c", "echo first >&2; echo last >&2; exit 1"])
+      .message("Failing")
+      .run()
+      .unwrap_err()
+      .to_string();
+    assert!(err.starts_with("Failing (exit status Exited(1))"));
+    assert!(err.ends_with("stderr:\nfirst\nlast"));
   }
 
   #[test]
@@ -1241,4 +2770,52 @@ mod tests {
       _ => panic!("Clone should preserve variant and value"),
     }
   }
+
+  /// Runs a remote command string with `sh -c`, as sshd does with the login
+  /// shell, and returns the NUL separated words it prints.
+  fn run_remote(line: &CommandLine) -> Vec<Vec<u8>> {
+    let output = process::Command::new("sh")
+      .arg("-c")
+      .arg(line.to_remote_command())
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+
+    let mut words: Vec<Vec<u8>> = output
+      .stdout
+      .split(|byte| *byte == 0)
+      .map(<[u8]>::to_vec)
+      .collect();
+    // The output ends with a NUL
+    words.pop();
+    words
+  }
+
+  proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_remote_command_keeps_argv_and_env(
+      args in prop::collection::vec(
+        prop::collection::vec(1u8..=255, 0..16),
+        0..6,
+      ),
+      value in "[^\x00]*",
+    ) {
+      let line = CommandLine {
+        env:  vec![("NH_REMOTE_VALUE".to_string(), value.clone())],
+        argv: vec![
+          OsString::from("sh"),
+          OsString::from("-c"),
+          OsString::from(r#"printf '%s\0' "$NH_REMOTE_VALUE" "$@""#),
+          OsString::from("sh"),
+        ],
+      }
+      .args(args.iter().map(|arg| OsStr::from_bytes(arg)));
+
+      let words = run_remote(&line);
+      prop_assert_eq!(&words[0], value.as_bytes());
+      prop_assert_eq!(&words[1..], &args[..]);
+    }
+  }
 }
@@ -1,12 +1,13 @@
 use std::{env, path::PathBuf};
 
-use color_eyre::eyre::{Context, bail, eyre};
+use color_eyre::eyre::{Context, bail};
 use tracing::{debug, warn};
 
 use crate::{
   Result,
   commands,
-  commands::{Command, ElevationStrategy},
+  commands::{Command, ElevationStrategy, SudoKeepAlive},
+  git,
   installable::Installable,
   interface::{
     DarwinArgs,
@@ -61,9 +62,22 @@ impl DarwinRebuildArgs {
       bail!("Don't run nh os as root. I will call sudo internally as needed");
     }
 
-    if self.update_args.update_all || self.update_args.update_input.is_some() {
-      update(&self.common.installable, self.update_args.update_input)?;
-    }
+    // Authenticate before a long build rather than after it, when nobody may
+    // be around to type the password
+    let _sudo_keepalive = if matches!(variant, Switch)
+      && !self.common.dry
+      && !self.no_sudo_keepalive
+    {
+      SudoKeepAlive::start(&elevation)?
+    } else {
+      None
+    };
+
+    let lock_snapshot = update(
+      &self.common.installable,
+      self.update_args,
+      Some(elevation.clone()),
+    )?;
 
     let hostname = self.hostname.ok_or(()).or_else(|()| get_hostname())?;
 
@@ -81,24 +95,19 @@ impl DarwinRebuildArgs {
     let installable = if let Ok(darwin_flake) = env::var("NH_DARWIN_FLAKE") {
       debug!("Using NH_DARWIN_FLAKE: {}", darwin_flake);
 
-      let mut elems = darwin_flake.splitn(2, '#');
-      let reference = match elems.next() {
-        Some(r) => r.to_owned(),
-        None => return Err(eyre!("NH_DARWIN_FLAKE missing reference part")),
-      };
-      let attribute = elems
-        .next()
-        .map(crate::installable::parse_attribute)
-        .unwrap_or_default();
-
-      Installable::Flake {
-        reference,
-        attribute,
-      }
+      Installable::from_flake_str(&darwin_flake)
+        .wrap_err("Invalid flake reference in NH_DARWIN_FLAKE")?
     } else {
       self.common.installable.clone()
     };
 
+    git::check_untracked_files(
+      &installable,
+      self.common.add_untracked,
+      self.common.ask,
+      self.common.dry,
+    )?;
+
     let mut processed_installable = installable;
     if let Installable::Flake {
       ref mut attribute, ..
@@ -115,8 +124,7 @@ impl DarwinRebuildArgs {
     let toplevel = toplevel_for(hostname, processed_installable, "toplevel");
 
     commands::Build::new(toplevel)
-      .extra_arg("--out-link")
-      .extra_arg(&out_path)
+      .out_link(&out_path)
       .extra_args(&self.extra_args)
       .passthrough(&self.common.passthrough)
       .message("Building Darwin configuration")
@@ -189,6 +197,10 @@ impl DarwinRebuildArgs {
 
     debug!("Completed operation with output path: {out_path:?}");
 
+    if let Some(snapshot) = lock_snapshot {
+      snapshot.keep();
+    }
+
     Ok(())
   }
 }
@@ -200,20 +212,8 @@ impl DarwinReplArgs {
       if let Ok(darwin_flake) = env::var("NH_DARWIN_FLAKE") {
         debug!("Using NH_DARWIN_FLAKE: {}", darwin_flake);
 
-        let mut elems = darwin_flake.splitn(2, '#');
-        let reference = match elems.next() {
-          Some(r) => r.to_owned(),
-          None => return Err(eyre!("NH_DARWIN_FLAKE missing reference part")),
-        };
-        let attribute = elems
-          .next()
-          .map(crate::installable::parse_attribute)
-          .unwrap_or_default();
-
-        Installable::Flake {
-          reference,
-          attribute,
-        }
+        Installable::from_flake_str(&darwin_flake)
+          .wrap_err("Invalid flake reference in NH_DARWIN_FLAKE")?
       } else {
         self.installable
       };
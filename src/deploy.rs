@@ -0,0 +1,345 @@
+//! Deploying one flake to several hosts at once.
+//!
+//! Hosts are given as repeated `--target-host` flags or as an inventory file.
+//! The configurations are built one after the other, as Nix already builds in
+//! parallel, and then copied and activated on a bounded number of hosts at a
+//! time. Every host is attempted even when others fail, and the ones that
+//! failed can be deployed again on their own.
+use std::{
+  collections::BTreeMap,
+  ffi::OsString,
+  fmt,
+  net::IpAddr,
+  num::NonZeroUsize,
+  path::Path,
+  str::FromStr,
+  sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{Context, bail};
+use owo_colors::OwoColorize;
+
+use crate::{Result, ssh::SshTarget};
+
+/// A host to deploy to, with the name of the configuration it gets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeployTarget {
+  /// The `nixosConfigurations` attribute, if given as `NAME=TARGET`.
+  pub name:   Option<String>,
+  pub target: SshTarget,
+}
+
+impl FromStr for DeployTarget {
+  type Err = color_eyre::Report;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.split_once('=') {
+      Some(("", _)) => bail!("Empty configuration name in target '{s}'"),
+      Some((name, target)) => {
+        Ok(Self {
+          name:   Some(name.to_string()),
+          target: target.parse()?,
+        })
+      },
+      None => {
+        Ok(Self {
+          name:   None,
+          target: s.parse()?,
+        })
+      },
+    }
+  }
+}
+
+impl fmt::Display for DeployTarget {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.name {
+      Some(name) => write!(f, "{name}={}", self.target),
+      None => write!(f, "{}", self.target),
+    }
+  }
+}
+
+impl DeployTarget {
+  /// Returns the name of the configuration to deploy: the one given with the
+  /// target, or else the short host name of the target.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the target is an IP address and has no name.
+  pub fn configuration(&self) -> Result<String> {
+    if let Some(name) = &self.name {
+      return Ok(name.clone());
+    }
+    if self.target.host.parse::<IpAddr>().is_ok() {
+      bail!(
+        "Cannot tell which configuration to deploy to {}, pass it as NAME={}",
+        self.target,
+        self.target
+      );
+    }
+    let host = &self.target.host;
+    Ok(host.split('.').next().unwrap_or(host).to_string())
+  }
+}
+
+/// Reads an inventory, a JSON object mapping `nixosConfigurations` names to
+/// ssh targets.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or a target is invalid.
+pub fn read_inventory(path: &Path) -> Result<Vec<DeployTarget>> {
+  let contents = std::fs::read_to_string(path)
+    .with_context(|| format!("Failed to read inventory {}", path.display()))?;
+  parse_inventory(&contents)
+    .with_context(|| format!("Invalid inventory {}", path.display()))
+}
+
+fn parse_inventory(contents: &str) -> Result<Vec<DeployTarget>> {
+  let hosts: BTreeMap<String, String> = serde_json::from_str(contents)?;
+  if hosts.is_empty() {
+    bail!("The inventory has no hosts");
+  }
+  hosts
+    .into_iter()
+    .map(|(name, target)| {
+      Ok(DeployTarget {
+        target: target
+          .parse()
+          .with_context(|| format!("Invalid target for {name}"))?,
+        name:   Some(name),
+      })
+    })
+    .collect()
+}
+
+/// What happened to a host, for the summary.
+#[derive(Debug)]
+pub struct Outcome {
+  pub configuration: String,
+  pub target:        DeployTarget,
+  pub result:        Result<Duration>,
+}
+
+/// Runs `deploy` for every host, on at most `parallel` hosts at a time, and
+/// returns the outcomes in the order of `hosts`.
+pub fn run_parallel<T: Sync>(
+  hosts: &[T],
+  parallel: NonZeroUsize,
+  deploy: impl Fn(&T) -> Result<()> + Sync,
+) -> Vec<Result<Duration>> {
+  let next = AtomicUsize::new(0);
+  let results: Mutex<Vec<Option<Result<Duration>>>> =
+    Mutex::new(hosts.iter().map(|_| None).collect());
+
+  thread::scope(|scope| {
+    for _ in 0..parallel.get().min(hosts.len()) {
+      scope.spawn(|| {
+        loop {
+          let index = next.fetch_add(1, Ordering::SeqCst);
+          let Some(host) = hosts.get(index) else {
+            break;
+          };
+          let start = Instant::now();
+          let result = deploy(host).map(|()| start.elapsed());
+          results.lock().unwrap_or_else(|e| e.into_inner())[index] =
+            Some(result);
+        }
+      });
+    }
+  });
+
+  results
+    .into_inner()
+    .unwrap_or_else(|e| e.into_inner())
+    .into_iter()
+    .map(|result| result.unwrap_or_else(|| bail!("Not deployed")))
+    .collect()
+}
+
+/// Prints a table with the result of every host.
+pub fn print_summary(outcomes: &[Outcome]) {
+  let name_width = outcomes
+    .iter()
+    .map(|o| o.configuration.len())
+    .chain(["Configuration".len()])
+    .max()
+    .unwrap_or_default();
+  let target_width = outcomes
+    .iter()
+    .map(|o| o.target.target.to_string().len())
+    .chain(["Target".len()])
+    .max()
+    .unwrap_or_default();
+
+  println!();
+  println!(
+    "{:<name_width$}  {:<target_width$}  Result",
+    "Configuration", "Target"
+  );
+  for outcome in outcomes {
+    let result = match &outcome.result {
+      Ok(elapsed) => {
+        format!("ok ({})", humantime::format_duration(round(*elapsed)))
+          .green()
+          .to_string()
+      },
+      Err(err) => format!("failed: {err}").red().to_string(),
+    };
+    println!(
+      "{:<name_width$}  {:<target_width$}  {result}",
+      outcome.configuration,
+      outcome.target.target.to_string()
+    );
+  }
+}
+
+fn round(elapsed: Duration) -> Duration {
+  Duration::from_secs(elapsed.as_secs())
+}
+
+/// Returns the command line that deploys to `failed` alone: the arguments of
+/// this invocation with every target and inventory replaced by them.
+pub fn retry_command(
+  args: impl IntoIterator<Item = OsString>,
+  failed: &[&DeployTarget],
+) -> Vec<OsString> {
+  const FLAGS: [&str; 2] = ["--target-host", "--inventory"];
+
+  let mut retry = Vec::new();
+  let mut args = args.into_iter();
+  while let Some(arg) = args.next() {
+    // Everything after -- is passed to nix build
+    if arg == "--" {
+      retry.push(arg);
+      retry.extend(args.by_ref());
+      break;
+    }
+    let flag = arg.to_str().unwrap_or_default();
+    if FLAGS.contains(&flag) {
+      args.next();
+      continue;
+    }
+    if FLAGS
+      .iter()
+      .any(|f| flag.strip_prefix(f).is_some_and(|v| v.starts_with('=')))
+    {
+      continue;
+    }
+    retry.push(arg);
+  }
+
+  // The flags must come before the arguments for nix build
+  let position = retry
+    .iter()
+    .position(|arg| arg == "--")
+    .unwrap_or(retry.len());
+  let targets = failed.iter().flat_map(|target| {
+    [
+      OsString::from("--target-host"),
+      OsString::from(target.to_string()),
+    ]
+  });
+  retry.splice(position..position, targets);
+  retry
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn target(s: &str) -> DeployTarget {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn test_configuration_name() {
+    assert_eq!(
+      target("web1=root@10.0.0.1").configuration().unwrap(),
+      "web1"
+    );
+    assert_eq!(
+      target("root@web1.example.com:2222")
+        .configuration()
+        .unwrap(),
+      "web1"
+    );
+    assert!(target("root@10.0.0.1").configuration().is_err());
+    assert!("=host".parse::<DeployTarget>().is_err());
+    assert_eq!(target("db=ssh-ng://db:22").to_string(), "db=ssh-ng://db:22");
+  }
+
+  #[test]
+  fn test_parse_inventory() {
+    let hosts =
+      parse_inventory(r#"{"web2": "root@10.0.0.2", "db": "ssh-ng://db"}"#)
+        .unwrap();
+    assert_eq!(hosts, [
+      target("db=ssh-ng://db"),
+      target("web2=root@10.0.0.2")
+    ]);
+
+    assert!(parse_inventory("{}").is_err());
+    assert!(parse_inventory(r#"{"web": "https://web"}"#).is_err());
+  }
+
+  #[test]
+  fn test_run_parallel_is_bounded() {
+    let running = AtomicUsize::new(0);
+    let most = AtomicUsize::new(0);
+    let hosts: Vec<usize> = (0..8).collect();
+
+    let results = run_parallel(&hosts, NonZeroUsize::new(3).unwrap(), |host| {
+      let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+      most.fetch_max(now, Ordering::SeqCst);
+      thread::sleep(Duration::from_millis(20));
+      running.fetch_sub(1, Ordering::SeqCst);
+      if host % 2 == 0 {
+        Ok(())
+      } else {
+        bail!("host {host} failed")
+      }
+    });
+
+    assert_eq!(most.load(Ordering::SeqCst), 3);
+    for (host, result) in results.iter().enumerate() {
+      assert_eq!(result.is_ok(), host % 2 == 0, "{host}");
+    }
+  }
+
+  #[test]
+  fn test_retry_command() {
+    let args = [
+      "nh",
+      "os",
+      "switch",
+      "--target-host",
+      "a",
+      "--inventory=hosts.json",
+      ".",
+      "--",
+      "--target-host",
+      "kept",
+    ]
+    .map(OsString::from);
+    let web = target("web=root@web");
+
+    assert_eq!(retry_command(args, &[&web]), [
+      "nh",
+      "os",
+      "switch",
+      ".",
+      "--target-host",
+      "web=root@web",
+      "--",
+      "--target-host",
+      "kept",
+    ]);
+  }
+}
@@ -0,0 +1,473 @@
+pub mod lock;
+
+use std::{env, fmt, path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, eyre};
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+pub use self::lock::FlakeLock;
+use crate::{
+  Result,
+  installable::Installable,
+  interface::{FlakeArgs, FlakeStatusArgs, FlakeSubcommand, FlakeUpdateArgs},
+  update::update_inputs,
+};
+
+impl FlakeArgs {
+  pub fn run(self) -> Result<()> {
+    match self.subcommand {
+      FlakeSubcommand::Update(args) => args.run(),
+      FlakeSubcommand::Status(args) => args.run(),
+    }
+  }
+}
+
+impl FlakeUpdateArgs {
+  pub fn run(self) -> Result<()> {
+    let installable = Installable::from_flake_str(&self.flake)?;
+
+    let inputs = if self.interactive {
+      let selected = select_inputs(&installable)?;
+      if selected.is_empty() {
+        info!("No inputs selected, nothing to update");
+        return Ok(());
+      }
+      Some(selected)
+    } else {
+      (!self.inputs.is_empty()).then_some(self.inputs)
+    };
+
+    let changes = update_inputs(&installable, inputs)?;
+
+    if self.json {
+      println!("{}", serde_json::to_string_pretty(&changes)?);
+    } else if let Some(changes) = changes {
+      print_changes(&changes);
+    }
+
+    Ok(())
+  }
+}
+
+impl FlakeStatusArgs {
+  pub fn run(self) -> Result<()> {
+    let installable = Installable::from_flake_str(&self.flake)?;
+    let lock_path = local_lock_path(&installable)
+      .ok_or_else(|| eyre!("{} is not a local flake", self.flake))?;
+    let Some(lock) = FlakeLock::read_if_exists(&lock_path)? else {
+      bail!("{} does not exist", lock_path.display());
+    };
+
+    let stale = stale_inputs(&lock, *self.max_age, Utc::now());
+
+    if self.json {
+      println!("{}", serde_json::to_string_pretty(&stale)?);
+    } else if stale.is_empty() {
+      println!(
+        "All inputs in {} were updated within the last {}",
+        lock_path.display(),
+        self.max_age
+      );
+    } else {
+      print_stale_inputs(&stale, &self.max_age);
+    }
+
+    if self.check && !stale.is_empty() {
+      bail!(
+        "{} flake input{} older than {}",
+        stale.len(),
+        if stale.len() == 1 { " is" } else { "s are" },
+        self.max_age
+      );
+    }
+
+    Ok(())
+  }
+}
+
+/// An input whose locked revision is older than the allowed age.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StaleInput {
+  pub name:          String,
+  pub rev:           String,
+  pub url:           Option<String>,
+  pub last_modified: DateTime<Utc>,
+  /// Whether this input is a nixpkgs checkout
+  pub nixpkgs:       bool,
+}
+
+/// Returns the inputs that were last modified more than `max_age` before
+/// `now`, oldest first. Inputs without a modification date, like paths, are
+/// never stale.
+pub fn stale_inputs(
+  lock: &FlakeLock,
+  max_age: Duration,
+  now: DateTime<Utc>,
+) -> Vec<StaleInput> {
+  let max_age =
+    chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+
+  let mut stale: Vec<_> = lock
+    .updatable_inputs()
+    .into_iter()
+    .filter_map(|input| {
+      let locked = input.locked?;
+      let last_modified = locked.last_modified()?;
+      (now - last_modified > max_age).then(|| {
+        StaleInput {
+          nixpkgs: locked.repo.as_deref() == Some("nixpkgs")
+            || input.name.rsplit('/').next() == Some("nixpkgs"),
+          rev: locked.short_rev(),
+          url: locked.url(),
+          last_modified,
+          name: input.name,
+        }
+      })
+    })
+    .collect();
+
+  stale.sort_by_key(|input| input.last_modified);
+  stale
+}
+
+fn print_stale_inputs(stale: &[StaleInput], max_age: &humantime::Duration) {
+  let now = Utc::now();
+  let width = stale
+    .iter()
+    .map(|input| input.name.len())
+    .max()
+    .unwrap_or_default();
+
+  println!("Inputs older than {max_age}:");
+  for input in stale {
+    let name = format!("{:<width$}", input.name);
+    let age = format!(
+      "{} ({})",
+      input.last_modified.format("%Y-%m-%d"),
+      lock::format_age(input.last_modified, now)
+    );
+
+    if input.nixpkgs {
+      println!(
+        "  {}  {:<8} {}",
+        name.yellow().bold(),
+        input.rev,
+        age.yellow()
+      );
+    } else {
+      println!("  {name}  {:<8} {age}", input.rev);
+    }
+  }
+}
+
+/// Warns about stale inputs before a rebuild if `NH_FLAKE_MAX_AGE` is set.
+pub fn warn_stale_inputs(installable: &Installable) {
+  let Ok(max_age) = env::var("NH_FLAKE_MAX_AGE") else {
+    return;
+  };
+  let max_age = match max_age.parse::<humantime::Duration>() {
+    Ok(max_age) => max_age,
+    Err(err) => {
+      warn!("Ignoring invalid NH_FLAKE_MAX_AGE={max_age}: {err}");
+      return;
+    },
+  };
+
+  let Some(lock_path) = local_lock_path(installable) else {
+    return;
+  };
+  let lock = match FlakeLock::read_if_exists(&lock_path) {
+    Ok(Some(lock)) => lock,
+    Ok(None) => return,
+    Err(err) => {
+      debug!("Not checking for stale inputs: {err:#}");
+      return;
+    },
+  };
+
+  let now = Utc::now();
+  for input in stale_inputs(&lock, *max_age, now) {
+    warn!(
+      "Flake input {} was last updated {}, consider running nh flake update",
+      input.name,
+      lock::format_age(input.last_modified, now)
+    );
+  }
+}
+
+/// Returns the path of the lock file of a local flake.
+fn local_lock_path(installable: &Installable) -> Option<PathBuf> {
+  match installable {
+    Installable::Flake { reference, .. } => {
+      reference.flake_dir().map(|dir| dir.join("flake.lock"))
+    },
+    _ => None,
+  }
+}
+
+/// An entry in the list of inputs offered by `--interactive`.
+struct InputChoice {
+  name:  String,
+  rev:   String,
+  age:   String,
+  width: usize,
+}
+
+impl fmt::Display for InputChoice {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{:<width$}  {:<8} {}",
+      self.name,
+      self.rev,
+      self.age,
+      width = self.width
+    )
+  }
+}
+
+/// Asks which inputs of a local flake to update, returning their input paths.
+fn select_inputs(installable: &Installable) -> Result<Vec<String>> {
+  let lock_path = local_lock_path(installable)
+    .ok_or_else(|| eyre!("--interactive needs a local flake"))?;
+
+  let Some(lock) = FlakeLock::read_if_exists(&lock_path)? else {
+    bail!(
+      "{} does not exist yet, run without --interactive to create it",
+      lock_path.display()
+    );
+  };
+
+  let now = Utc::now();
+  let inputs = lock.updatable_inputs();
+  let width = inputs
+    .iter()
+    .map(|input| input.name.len())
+    .max()
+    .unwrap_or_default();
+
+  let choices: Vec<_> = inputs
+    .into_iter()
+    .map(|input| {
+      InputChoice {
+        rev: input
+          .locked
+          .map_or_else(|| "unlocked".to_string(), lock::Locked::short_rev),
+        age: input
+          .locked
+          .and_then(lock::Locked::last_modified)
+          .map(|date| lock::format_age(date, now))
+          .unwrap_or_default(),
+        name: input.name,
+        width,
+      }
+    })
+    .collect();
+
+  if choices.is_empty() {
+    bail!("{} has no inputs to update", lock_path.display());
+  }
+
+  let selected =
+    inquire::MultiSelect::new("Select the inputs to update:", choices)
+      .with_page_size(15)
+      .prompt()?;
+
+  Ok(selected.into_iter().map(|choice| choice.name).collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+  Added,
+  Removed,
+  Updated,
+  Unchanged,
+}
+
+/// How a single input changed between two versions of a lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputChange {
+  pub name: String,
+  pub kind: ChangeKind,
+  pub url:  Option<String>,
+  pub old:  Option<lock::Locked>,
+  pub new:  Option<lock::Locked>,
+}
+
+/// Compares the inputs of two lock files, including nested inputs that are
+/// locked separately. `old` is `None` when the flake had not been locked
+/// before.
+pub fn diff_locks(
+  old: Option<&FlakeLock>,
+  new: &FlakeLock,
+) -> Vec<InputChange> {
+  let old_inputs = old.map(FlakeLock::updatable_inputs).unwrap_or_default();
+  let new_inputs = new.updatable_inputs();
+
+  let mut names: Vec<&str> = old_inputs
+    .iter()
+    .chain(&new_inputs)
+    .map(|input| input.name.as_str())
+    .collect();
+  names.sort_unstable();
+  names.dedup();
+
+  names
+    .into_iter()
+    .map(|name| {
+      let find = |inputs: &[lock::Input<'_>]| {
+        inputs
+          .iter()
+          .find(|input| input.name == name)
+          .and_then(|input| input.locked.cloned())
+      };
+      let old = find(&old_inputs);
+      let new = find(&new_inputs);
+
+      let kind = match (&old, &new) {
+        (None, Some(_)) => ChangeKind::Added,
+        (Some(_), None) => ChangeKind::Removed,
+        (Some(old), Some(new)) if !old.same_as(new) => ChangeKind::Updated,
+        _ => ChangeKind::Unchanged,
+      };
+
+      InputChange {
+        name: name.to_string(),
+        kind,
+        url: new.as_ref().or(old.as_ref()).and_then(lock::Locked::url),
+        old,
+        new,
+      }
+    })
+    .collect()
+}
+
+/// Prints a short table for every input that changed.
+pub fn print_changes(changes: &[InputChange]) {
+  let changed: Vec<_> = changes
+    .iter()
+    .filter(|change| change.kind != ChangeKind::Unchanged)
+    .collect();
+
+  if changed.is_empty() {
+    println!("All updated inputs were already up to date");
+    return;
+  }
+
+  let now = Utc::now();
+  let describe = |locked: &lock::Locked| {
+    let date = locked.last_modified().map_or_else(
+      || "unknown date".to_string(),
+      |date| {
+        format!(
+          "{} ({})",
+          date.format("%Y-%m-%d"),
+          lock::format_age(date, now)
+        )
+      },
+    );
+    format!("{:<8} {date}", locked.short_rev())
+  };
+
+  println!(
+    "Changed {} flake input{}:",
+    changed.len(),
+    if changed.len() == 1 { "" } else { "s" }
+  );
+
+  for change in changed {
+    println!();
+    println!(
+      "{}  {}",
+      change.name.bold(),
+      change.url.as_deref().unwrap_or_default().dimmed()
+    );
+    if let Some(old) = &change.old {
+      println!("  {}  {}", "old".red(), describe(old));
+    }
+    if let Some(new) = &change.new {
+      println!("  {}  {}", "new".green(), describe(new));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lock(nixpkgs_rev: &str, extra_input: bool) -> FlakeLock {
+    let extra_node = if extra_input {
+      r#""hm": { "locked": { "type": "github", "owner": "nix-community", "repo": "home-manager", "rev": "cccc", "lastModified": 1 } },"#
+    } else {
+      ""
+    };
+    let extra_root = if extra_input { r#", "hm": "hm""# } else { "" };
+
+    format!(
+      r#"{{
+        "nodes": {{
+          {extra_node}
+          "nixpkgs": {{ "locked": {{ "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "{nixpkgs_rev}", "lastModified": 1 }} }},
+          "root": {{ "inputs": {{ "nixpkgs": "nixpkgs"{extra_root} }} }}
+        }},
+        "root": "root",
+        "version": 7
+      }}"#
+    )
+    .parse()
+    .unwrap()
+  }
+
+  #[test]
+  fn test_diff_locks() {
+    let old = lock("aaaa", true);
+    let new = lock("bbbb", false);
+
+    let kinds: Vec<_> = diff_locks(Some(&old), &new)
+      .into_iter()
+      .map(|change| (change.name, change.kind))
+      .collect();
+    assert_eq!(kinds, vec![
+      ("hm".to_string(), ChangeKind::Removed),
+      ("nixpkgs".to_string(), ChangeKind::Updated),
+    ]);
+
+    assert!(
+      diff_locks(Some(&new), &new)
+        .iter()
+        .all(|change| change.kind == ChangeKind::Unchanged)
+    );
+  }
+
+  #[test]
+  fn test_stale_inputs() {
+    let lock = lock("aaaa", true);
+    let day = Duration::from_secs(86400);
+    let long_ago = DateTime::from_timestamp(1 + 10 * 86400, 0).unwrap();
+
+    let stale = stale_inputs(&lock, day, long_ago);
+    let names: Vec<_> = stale
+      .iter()
+      .map(|input| (input.name.as_str(), input.nixpkgs))
+      .collect();
+    assert_eq!(names, vec![("hm", false), ("nixpkgs", true)]);
+
+    assert!(stale_inputs(&lock, 20 * day, long_ago).is_empty());
+  }
+
+  #[test]
+  fn test_diff_locks_without_previous_lock() {
+    let changes = diff_locks(None, &lock("aaaa", false));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::Added);
+    assert_eq!(
+      changes[0].url.as_deref(),
+      Some("https://github.com/NixOS/nixpkgs")
+    );
+  }
+}
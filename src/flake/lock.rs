@@ -0,0 +1,310 @@
+//! A read-only model of `flake.lock`.
+//!
+//! Only the parts nh needs to describe an input are modelled: the graph of
+//! nodes and the `locked` attributes of each node. Unknown attributes are
+//! ignored so newer lock file versions keep working.
+use std::{
+  collections::{BTreeMap, BTreeSet, VecDeque},
+  fs,
+  path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlakeLock {
+  nodes:   BTreeMap<String, Node>,
+  root:    String,
+  #[allow(dead_code)]
+  version: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Node {
+  #[serde(default)]
+  inputs: BTreeMap<String, InputRef>,
+  locked: Option<Locked>,
+}
+
+/// An edge in the lock graph. Inputs either point at a node directly, or
+/// follow a path of input names starting from the root node.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum InputRef {
+  Node(String),
+  Follows(Vec<String>),
+}
+
+/// The `locked` attributes of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Locked {
+  #[serde(rename = "type")]
+  pub kind:          String,
+  pub owner:         Option<String>,
+  pub repo:          Option<String>,
+  pub url:           Option<String>,
+  pub path:          Option<String>,
+  pub host:          Option<String>,
+  pub rev:           Option<String>,
+  #[serde(rename = "ref")]
+  pub git_ref:       Option<String>,
+  pub last_modified: Option<i64>,
+  pub nar_hash:      Option<String>,
+}
+
+/// A resolved input of the root flake. Nested inputs are named by their
+/// input path, e.g. `home-manager/nixpkgs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input<'a> {
+  pub name:   String,
+  pub locked: Option<&'a Locked>,
+}
+
+impl FlakeLock {
+  /// Reads and parses a `flake.lock` file.
+  pub fn read(path: &Path) -> Result<Self> {
+    let contents = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read {}", path.display()))?;
+    contents
+      .parse()
+      .with_context(|| format!("Failed to parse {}", path.display()))
+  }
+
+  /// Reads `path` if it exists, returning `None` for flakes that have not
+  /// been locked yet.
+  pub fn read_if_exists(path: &Path) -> Result<Option<Self>> {
+    if path.exists() {
+      Self::read(path).map(Some)
+    } else {
+      Ok(None)
+    }
+  }
+
+  /// Returns every input that can be updated on its own: the root inputs
+  /// followed by nested inputs that are locked separately, in breadth-first
+  /// order. Inputs that `follows` another input are skipped, as they are
+  /// updated along with the input they follow.
+  pub fn updatable_inputs(&self) -> Vec<Input<'_>> {
+    let mut inputs = Vec::new();
+    let mut visited = BTreeSet::from([self.root.as_str()]);
+    let mut queue = VecDeque::from([(self.root.as_str(), Vec::new())]);
+
+    while let Some((node, path)) = queue.pop_front() {
+      let Some(node) = self.nodes.get(node) else {
+        continue;
+      };
+
+      for (name, input) in &node.inputs {
+        let InputRef::Node(target) = input else {
+          continue;
+        };
+        let Some((target, target_node)) = self.nodes.get_key_value(target)
+        else {
+          continue;
+        };
+        if !visited.insert(target.as_str()) {
+          continue;
+        }
+
+        let mut path = path.clone();
+        path.push(name.clone());
+
+        inputs.push(Input {
+          name:   path.join("/"),
+          locked: target_node.locked.as_ref(),
+        });
+        queue.push_back((target.as_str(), path));
+      }
+    }
+
+    inputs
+  }
+}
+
+impl std::str::FromStr for FlakeLock {
+  type Err = color_eyre::Report;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let lock: Self = serde_json::from_str(s)?;
+    if !lock.nodes.contains_key(&lock.root) {
+      bail!("Root node '{}' is missing from the lock file", lock.root);
+    }
+    Ok(lock)
+  }
+}
+
+impl Locked {
+  /// Returns a URL a human can open to look at the input.
+  pub fn url(&self) -> Option<String> {
+    let owner = self.owner.as_deref();
+    let repo = self.repo.as_deref();
+
+    match (self.kind.as_str(), owner, repo) {
+      ("github", Some(owner), Some(repo)) => {
+        let host = self.host.as_deref().unwrap_or("github.com");
+        Some(format!("https://{host}/{owner}/{repo}"))
+      },
+      ("gitlab", Some(owner), Some(repo)) => {
+        let host = self.host.as_deref().unwrap_or("gitlab.com");
+        Some(format!("https://{host}/{owner}/{repo}"))
+      },
+      ("sourcehut", Some(owner), Some(repo)) => {
+        let host = self.host.as_deref().unwrap_or("git.sr.ht");
+        Some(format!("https://{host}/{owner}/{repo}"))
+      },
+      ("path", ..) => self.path.clone(),
+      _ => self.url.clone(),
+    }
+  }
+
+  /// Returns an abbreviated revision, or the NAR hash for inputs that are not
+  /// tied to a revision, such as tarballs.
+  pub fn short_rev(&self) -> String {
+    match (&self.rev, &self.nar_hash) {
+      (Some(rev), _) => rev.chars().take(7).collect(),
+      (None, Some(hash)) => hash.clone(),
+      (None, None) => "unknown".to_string(),
+    }
+  }
+
+  /// Returns the commit date of the input.
+  pub fn last_modified(&self) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(self.last_modified?, 0)
+  }
+
+  /// Returns true if both entries lock the same contents.
+  pub fn same_as(&self, other: &Self) -> bool {
+    match (&self.rev, &other.rev) {
+      (Some(a), Some(b)) => a == b,
+      _ => self.nar_hash == other.nar_hash,
+    }
+  }
+}
+
+/// Formats the time elapsed since `then` in the largest sensible unit, e.g.
+/// `3 days ago`.
+pub fn format_age(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+  let seconds = (now - then).num_seconds();
+  if seconds < 0 {
+    return "in the future".to_string();
+  }
+
+  let (amount, unit) = match seconds {
+    0..60 => return "just now".to_string(),
+    60..3600 => (seconds / 60, "minute"),
+    3600..86400 => (seconds / 3600, "hour"),
+    _ => (seconds / 86400, "day"),
+  };
+
+  format!(
+    "{amount} {unit}{plural} ago",
+    plural = if amount == 1 { "" } else { "s" }
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LOCK: &str = r#"{
+    "nodes": {
+      "home-manager": {
+        "inputs": { "nixpkgs": ["nixpkgs"] },
+        "locked": {
+          "lastModified": 1700000000,
+          "narHash": "sha256-hm",
+          "owner": "nix-community",
+          "repo": "home-manager",
+          "rev": "1111111111111111111111111111111111111111",
+          "type": "github"
+        },
+        "original": { "owner": "nix-community", "repo": "home-manager", "type": "github" }
+      },
+      "nixpkgs": {
+        "locked": {
+          "lastModified": 1710000000,
+          "narHash": "sha256-nixpkgs",
+          "owner": "NixOS",
+          "repo": "nixpkgs",
+          "rev": "2222222222222222222222222222222222222222",
+          "type": "github"
+        },
+        "original": { "id": "nixpkgs", "type": "indirect" }
+      },
+      "root": {
+        "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs" }
+      }
+    },
+    "root": "root",
+    "version": 7
+  }"#;
+
+  #[test]
+  fn test_locked_attributes() {
+    let lock: FlakeLock = LOCK.parse().unwrap();
+    let inputs = lock.updatable_inputs();
+
+    let nixpkgs = inputs[1].locked.unwrap();
+    assert_eq!(nixpkgs.short_rev(), "2222222");
+    assert_eq!(
+      nixpkgs.url().as_deref(),
+      Some("https://github.com/NixOS/nixpkgs")
+    );
+    assert_eq!(nixpkgs.last_modified().unwrap().timestamp(), 1_710_000_000);
+  }
+
+  #[test]
+  fn test_updatable_inputs() {
+    let lock: FlakeLock = LOCK.parse().unwrap();
+    let names: Vec<_> = lock
+      .updatable_inputs()
+      .into_iter()
+      .map(|input| input.name)
+      .collect();
+    // home-manager/nixpkgs follows the root nixpkgs input
+    assert_eq!(names, vec!["home-manager", "nixpkgs"]);
+
+    let nested = LOCK.replace(
+      r#""inputs": { "nixpkgs": ["nixpkgs"] },"#,
+      r#""inputs": { "nixpkgs": "nixpkgs_2" },"#,
+    );
+    let nested = nested.replace(
+      r#""root": {"#,
+      r#""nixpkgs_2": { "locked": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "3333" } },
+      "root": {"#,
+    );
+    let lock: FlakeLock = nested.parse().unwrap();
+    let names: Vec<_> = lock
+      .updatable_inputs()
+      .into_iter()
+      .map(|input| input.name)
+      .collect();
+    assert_eq!(names, vec![
+      "home-manager",
+      "nixpkgs",
+      "home-manager/nixpkgs"
+    ]);
+  }
+
+  #[test]
+  fn test_missing_root_is_an_error() {
+    assert!(r#"{"nodes": {}, "root": "root", "version": 7}"#
+      .parse::<FlakeLock>()
+      .is_err());
+  }
+
+  #[test]
+  fn test_format_age() {
+    let now = DateTime::from_timestamp(1_000_000, 0).unwrap();
+    let ago = |secs| format_age(now - chrono::Duration::seconds(secs), now);
+    assert_eq!(ago(5), "just now");
+    assert_eq!(ago(60), "1 minute ago");
+    assert_eq!(ago(7200), "2 hours ago");
+    assert_eq!(ago(86400 * 3), "3 days ago");
+  }
+}
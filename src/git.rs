@@ -0,0 +1,251 @@
+//! Checks on the git checkout a local flake is built from.
+use std::{
+  path::{Path, PathBuf},
+  process,
+};
+
+use color_eyre::eyre::{Context, bail};
+use tracing::{debug, info, warn};
+
+use crate::{Result, commands::Command, installable::Installable};
+
+/// Returns the root of the git work tree of a local git flake.
+///
+/// Returns `None` for other installables, and for paths that are not inside a
+/// git repository, which Nix copies as a whole.
+pub fn flake_worktree(installable: &Installable) -> Option<PathBuf> {
+  let Installable::Flake { reference, .. } = installable else {
+    return None;
+  };
+  if !reference.is_local_git() {
+    return None;
+  }
+
+  let dir = reference.flake_dir()?;
+  match git_output(&dir, &["rev-parse", "--show-toplevel"]) {
+    Ok(toplevel) => Some(PathBuf::from(toplevel.trim_end())),
+    Err(err) => {
+      debug!("{} is not a git work tree: {err:#}", dir.display());
+      None
+    },
+  }
+}
+
+/// Runs a git command in `dir` and returns its standard output. Unlike
+/// [`Command::run_capture`], this keeps git's error messages out of the
+/// terminal, as failing here is often expected.
+fn git_output(dir: &Path, args: &[&str]) -> Result<String> {
+  let output = process::Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .args(args)
+    .output()
+    .context("Failed to run git")?;
+
+  if !output.status.success() {
+    bail!(
+      "git {} failed: {}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+  }
+
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Lists the untracked `.nix` files of a work tree that are not ignored,
+/// relative to its root.
+pub fn untracked_nix_files(worktree: &Path) -> Result<Vec<String>> {
+  let output = git_output(worktree, &[
+    "ls-files",
+    "-z",
+    "--others",
+    "--exclude-standard",
+    "--",
+    "*.nix",
+  ])
+  .context("Failed to list untracked files")?;
+
+  Ok(parse_ls_files(&output))
+}
+
+/// Lists the tracked files of a work tree with uncommitted changes, staged or
+/// not, relative to its root.
+pub fn dirty_files(worktree: &Path) -> Result<Vec<String>> {
+  let output = git_output(worktree, &[
+    "status",
+    "--porcelain=v1",
+    "-z",
+    "--untracked-files=no",
+  ])
+  .context("Failed to get the status of the work tree")?;
+
+  Ok(parse_status(&output))
+}
+
+/// Parses the output of `git status --porcelain=v1 -z` into paths.
+fn parse_status(output: &str) -> Vec<String> {
+  let mut paths = Vec::new();
+  let mut entries = output.split('\0').filter(|entry| !entry.is_empty());
+
+  while let Some(entry) = entries.next() {
+    let (status, path) = entry.split_at_checked(3).unwrap_or((entry, ""));
+    paths.push(path.to_string());
+
+    // Renames and copies are followed by the original path
+    if status.starts_with(['R', 'C']) {
+      entries.next();
+    }
+  }
+
+  paths
+}
+
+/// Refuses to continue if a local git flake has uncommitted changes, so that
+/// the configuration revision of the result maps to a commit.
+///
+/// With `allow_dirty`, the changes are reported but the rebuild continues.
+/// Flakes that are not local are always clean.
+pub fn ensure_clean(
+  installable: &Installable,
+  allow_dirty: bool,
+) -> Result<()> {
+  let Installable::Flake { reference, .. } = installable else {
+    bail!("--require-clean only works with flakes");
+  };
+  if !reference.is_local() {
+    return Ok(());
+  }
+
+  let Some(worktree) = flake_worktree(installable) else {
+    bail!(
+      "--require-clean was given, but {reference} is not a git checkout so \
+       its revision cannot be verified"
+    );
+  };
+
+  let dirty = dirty_files(&worktree)?;
+  if dirty.is_empty() {
+    debug!("{} is clean", worktree.display());
+    return Ok(());
+  }
+
+  let listing = dirty
+    .iter()
+    .map(|path| format!("  {path}"))
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  if !allow_dirty {
+    bail!(
+      "Refusing to continue, --require-clean was given and {} has uncommitted \
+       changes:\n{listing}\n\nCommit them, or pass --allow-dirty to override",
+      worktree.display()
+    );
+  }
+
+  warn!(
+    "Continuing with uncommitted changes in {} because --allow-dirty was \
+     given, the configuration revision will not match a commit:\n{listing}",
+    worktree.display()
+  );
+
+  Ok(())
+}
+
+/// Parses the NUL separated output of `git ls-files -z`.
+fn parse_ls_files(output: &str) -> Vec<String> {
+  output
+    .split('\0')
+    .filter(|path| !path.is_empty())
+    .map(str::to_string)
+    .collect()
+}
+
+/// Warns about untracked `.nix` files in a local git flake, which Nix does not
+/// copy to the store and thus cannot be imported by the configuration.
+///
+/// With `add`, or if the user agrees when `ask` is set, the files are added
+/// with `git add --intent-to-add` so that Nix sees them without staging their
+/// contents.
+pub fn check_untracked_files(
+  installable: &Installable,
+  add: bool,
+  ask: bool,
+  dry: bool,
+) -> Result<()> {
+  let Some(worktree) = flake_worktree(installable) else {
+    return Ok(());
+  };
+
+  let untracked = match untracked_nix_files(&worktree) {
+    Ok(untracked) => untracked,
+    Err(err) => {
+      debug!("Not checking for untracked files: {err:#}");
+      return Ok(());
+    },
+  };
+
+  if untracked.is_empty() {
+    return Ok(());
+  }
+
+  warn!(
+    "The following files are not tracked by git and will not be visible to \
+     the flake:\n{}",
+    untracked
+      .iter()
+      .map(|path| format!("  {path}"))
+      .collect::<Vec<_>>()
+      .join("\n")
+  );
+
+  let add = add
+    || (ask
+      && !dry
+      && inquire::Confirm::new(
+        "Add them with `git add --intent-to-add` before building?",
+      )
+      .with_default(false)
+      .prompt()?);
+
+  if !add {
+    info!(
+      "Pass --add-untracked to add them with `git add --intent-to-add` before \
+       building"
+    );
+    return Ok(());
+  }
+
+  Command::new("git")
+    .arg("-C")
+    .arg(&worktree)
+    .args(["add", "--intent-to-add", "--"])
+    .args(&untracked)
+    .message("Adding untracked files with git add --intent-to-add")
+    .dry(dry)
+    .run()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_ls_files() {
+    assert_eq!(
+      parse_ls_files("hosts/new.nix\0modules/with space.nix\0"),
+      vec!["hosts/new.nix", "modules/with space.nix"]
+    );
+    assert!(parse_ls_files("").is_empty());
+  }
+
+  #[test]
+  fn test_parse_status() {
+    assert_eq!(
+      parse_status(" M flake.nix\0R  hosts/new.nix\0hosts/old.nix\0A  x.nix\0"),
+      vec!["flake.nix", "hosts/new.nix", "x.nix"]
+    );
+    assert!(parse_status("").is_empty());
+  }
+}
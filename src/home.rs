@@ -9,12 +9,27 @@ use tracing::{debug, info, warn};
 use crate::{
   commands,
   commands::Command,
+  git,
   installable::Installable,
   interface::{self, DiffType, HomeRebuildArgs, HomeReplArgs, HomeSubcommand},
+  ssh::{SshSession, SshTarget},
   update::update,
-  util::{get_hostname, print_dix_diff},
+  util::{
+    copy_from_remote,
+    ensure_ssh_key_login,
+    get_hostname,
+    print_dix_diff,
+  },
 };
 
+/// Prints the current Home Manager generation of the user, looking in the
+/// same places as for local switches.
+const REMOTE_GENERATION_SCRIPT: &str = r#"for profile in "/nix/var/nix/profiles/per-user/$USER/home-manager" "$HOME/.local/state/nix/profiles/home-manager"; do if [ -e "$profile" ]; then readlink -f "$profile"; exit; fi; done"#;
+
+/// Prints the specialisation the user switched to last, if any.
+const REMOTE_SPECIALISATION_SCRIPT: &str =
+  r#"cat "$HOME/.local/share/home-manager/specialisation" 2>/dev/null || true"#;
+
 impl interface::HomeArgs {
   /// Run the `home` subcommand.
   ///
@@ -46,10 +61,20 @@ impl HomeRebuildArgs {
   fn rebuild(self, variant: &HomeRebuildVariant) -> Result<()> {
     use HomeRebuildVariant::Build;
 
-    if self.update_args.update_all || self.update_args.update_input.is_some() {
-      update(&self.common.installable, self.update_args.update_input)?;
+    let lock_snapshot =
+      update(&self.common.installable, self.update_args, None)?;
+
+    if self.target_host.is_some() {
+      // if it fails its okay
+      let _ = ensure_ssh_key_login();
     }
 
+    // Every step of the deployment reuses one connection to the target
+    let _ssh_session = match &self.target_host {
+      Some(target) if !self.common.dry => Some(SshSession::start([target])?),
+      _ => None,
+    };
+
     let (out_path, _tempdir_guard): (PathBuf, Option<tempfile::TempDir>) =
       if let Some(ref p) = self.common.out_link {
         (p.clone(), None)
@@ -64,34 +89,36 @@ impl HomeRebuildArgs {
     let installable = if let Ok(home_flake) = env::var("NH_HOME_FLAKE") {
       debug!("Using NH_HOME_FLAKE: {}", home_flake);
 
-      let mut elems = home_flake.splitn(2, '#');
-      let reference = match elems.next() {
-        Some(r) => r.to_owned(),
-        None => return Err(eyre!("NH_HOME_FLAKE missing reference part")),
-      };
-      let attribute = elems
-        .next()
-        .map(crate::installable::parse_attribute)
-        .unwrap_or_default();
-
-      Installable::Flake {
-        reference,
-        attribute,
-      }
+      Installable::from_flake_str(&home_flake)
+        .wrap_err("Invalid flake reference in NH_HOME_FLAKE")?
     } else {
       self.common.installable.clone()
     };
 
+    git::check_untracked_files(
+      &installable,
+      self.common.add_untracked,
+      self.common.ask,
+      self.common.dry,
+    )?;
+
+    // Unless it is named, the configuration is picked for the user and host
+    // it is activated for
+    let identity = match (&self.target_host, &self.configuration) {
+      (Some(target), None) => Some(remote_identity(target)?),
+      _ => None,
+    };
+
     let toplevel = toplevel_for(
       installable,
       true,
       &self.extra_args,
       self.configuration.clone(),
+      identity,
     )?;
 
     commands::Build::new(toplevel)
-      .extra_arg("--out-link")
-      .extra_arg(&out_path)
+      .out_link(&out_path)
       .extra_args(&self.extra_args)
       .passthrough(&self.common.passthrough)
       .message("Building Home-Manager configuration")
@@ -99,28 +126,46 @@ impl HomeRebuildArgs {
       .run()
       .wrap_err("Failed to build Home-Manager configuration")?;
 
-    let prev_generation: Option<PathBuf> = [
-      PathBuf::from("/nix/var/nix/profiles/per-user")
-        .join(env::var("USER").map_err(|_| eyre!("Couldn't get username"))?)
-        .join("home-manager"),
-      PathBuf::from(
-        env::var("HOME").map_err(|_| eyre!("Couldn't get home directory"))?,
-      )
-      .join(".local/state/nix/profiles/home-manager"),
-    ]
-    .into_iter()
-    .find(|next| next.exists());
+    let prev_generation: Option<PathBuf> = match &self.target_host {
+      Some(_) if matches!(self.common.diff, DiffType::Never) => None,
+      Some(target) => remote_generation(target, self.common.dry)?,
+      None => {
+        [
+          PathBuf::from("/nix/var/nix/profiles/per-user")
+            .join(env::var("USER").map_err(|_| eyre!("Couldn't get username"))?)
+            .join("home-manager"),
+          PathBuf::from(
+            env::var("HOME")
+              .map_err(|_| eyre!("Couldn't get home directory"))?,
+          )
+          .join(".local/state/nix/profiles/home-manager"),
+        ]
+        .into_iter()
+        .find(|next| next.exists())
+      },
+    };
 
     debug!("Previous generation: {prev_generation:?}");
 
-    let spec_location = PathBuf::from(std::env::var("HOME")?)
-      .join(".local/share/home-manager/specialisation");
-
-    let current_specialisation = if let Some(s) = spec_location.to_str() {
-      std::fs::read_to_string(s).ok()
+    let current_specialisation = if let Some(target) = &self.target_host {
+      Command::new("sh")
+        .args(["-c", REMOTE_SPECIALISATION_SCRIPT])
+        .ssh(Some(target.clone()))
+        .dry(self.common.dry)
+        .run_capture()
+        .wrap_err("Failed to read the specialisation on the target host")?
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
     } else {
-      tracing::warn!("spec_location path is not valid UTF-8");
-      None
+      let spec_location = PathBuf::from(std::env::var("HOME")?)
+        .join(".local/share/home-manager/specialisation");
+
+      if let Some(s) = spec_location.to_str() {
+        std::fs::read_to_string(s).ok()
+      } else {
+        tracing::warn!("spec_location path is not valid UTF-8");
+        None
+      }
     };
 
     let target_specialisation = if self.no_specialisation {
@@ -149,14 +194,19 @@ impl HomeRebuildArgs {
       }
     }
 
-    if self.common.dry || matches!(variant, Build) {
-      if self.common.ask {
-        warn!("--ask has no effect as dry run was requested");
+    if matches!(variant, Build) {
+      if let Some(snapshot) = lock_snapshot {
+        snapshot.keep();
       }
       return Ok(());
     }
 
-    if self.common.ask {
+    if self.common.dry {
+      if self.common.ask {
+        warn!("--ask has no effect as dry run was requested");
+      }
+      info!("Dry run, printing the commands that would be run");
+    } else if self.common.ask {
       let confirmation = inquire::Confirm::new("Apply the config?")
         .with_default(false)
         .prompt()?;
@@ -173,23 +223,107 @@ impl HomeRebuildArgs {
       }
     }
 
-    Command::new(target_profile.join("activate"))
-      .with_required_env()
+    let activate = if let Some(target) = &self.target_host {
+      // The out link only exists here, the target needs the store path
+      let target_profile =
+        target_profile.canonicalize().wrap_err_with(|| {
+          format!("Failed to resolve {}", target_profile.display())
+        })?;
+
+      commands::Copy::to(target)
+        .path(&target_profile)
+        .options(&self.copy)
+        .message(format!("Copying configuration to {target}"))
+        .dry(self.common.dry)
+        .run()?;
+
+      // The activation script runs as the ssh user, with their own
+      // environment rather than ours
+      let activate =
+        Command::new(target_profile.join("activate")).ssh(Some(target.clone()));
+      match &self.backup_extension {
+        Some(ext) => activate.env("HOME_MANAGER_BACKUP_EXT", ext),
+        None => activate,
+      }
+    } else {
+      Command::new(target_profile.join("activate")).with_required_env()
+    };
+
+    activate
       .message("Activating configuration")
+      .dry(self.common.dry)
       .run()
       .wrap_err("Activation failed")?;
 
     debug!("Completed operation with output path: {target_profile:?}");
 
+    if let Some(snapshot) = lock_snapshot {
+      snapshot.keep();
+    }
+
     Ok(())
   }
 }
 
+/// Finds the current generation of the user on `target` and copies it here,
+/// so that the new one can be diffed against it. Returns `None` if the user
+/// has no generation yet, or if it could not be copied.
+fn remote_generation(target: &SshTarget, dry: bool) -> Result<Option<PathBuf>> {
+  let Some(generation) = Command::new("sh")
+    .args(["-c", REMOTE_GENERATION_SCRIPT])
+    .ssh(Some(target.clone()))
+    .dry(dry)
+    .run_capture()
+    .wrap_err("Failed to find the current generation on the target host")?
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+  else {
+    return Ok(None);
+  };
+
+  let generation = PathBuf::from(generation);
+  match copy_from_remote(target, &generation) {
+    Ok(()) => Ok(Some(generation)),
+    Err(e) => {
+      warn!(
+        "Not showing the diff, as the current generation on {target} could \
+         not be copied: {e}"
+      );
+      Ok(None)
+    },
+  }
+}
+
+/// Returns the user name and host name that `target` logs in as. They are
+/// looked up even in dry runs, as they decide what gets built.
+fn remote_identity(target: &SshTarget) -> Result<(String, String)> {
+  let output = Command::new("sh")
+    .args(["-c", "id -un && uname -n"])
+    .ssh(Some(target.clone()))
+    .run_capture()
+    .wrap_err_with(|| {
+      format!("Failed to find the user and host name of {target}")
+    })?
+    .unwrap_or_default();
+
+  let mut lines = output.lines().map(str::trim);
+  match (lines.next(), lines.next()) {
+    (Some(user), Some(host)) if !user.is_empty() && !host.is_empty() => {
+      Ok((user.to_string(), host.to_string()))
+    },
+    _ => bail!("Failed to find the user and host name of {target}"),
+  }
+}
+
+/// Returns the installable to build. Without an explicit configuration, it is
+/// looked up by `identity`, the user and host name it is for, which default to
+/// the local ones.
 fn toplevel_for<I, S>(
   installable: Installable,
   push_drv: bool,
   extra_args: I,
   configuration_name: Option<String>,
+  identity: Option<(String, String)>,
 ) -> Result<Installable>
 where
   I: IntoIterator<Item = S>,
@@ -281,9 +415,16 @@ where
 
       // If no explicit config was found via flag, try automatic detection
       if !found_config {
-        let username =
-          std::env::var("USER").map_err(|_| eyre!("Couldn't get username"))?;
-        let hostname = get_hostname()?;
+        let (username, hostname) = match identity {
+          Some(identity) => identity,
+          None => {
+            (
+              std::env::var("USER")
+                .map_err(|_| eyre!("Couldn't get username"))?,
+              get_hostname()?,
+            )
+          },
+        };
         let mut tried = vec![];
 
         for attr_name in [format!("{username}@{hostname}"), username] {
@@ -374,20 +515,8 @@ impl HomeReplArgs {
     let installable = if let Ok(home_flake) = env::var("NH_HOME_FLAKE") {
       debug!("Using NH_HOME_FLAKE: {home_flake}");
 
-      let mut elems = home_flake.splitn(2, '#');
-      let reference = match elems.next() {
-        Some(r) => r.to_owned(),
-        None => return Err(eyre!("NH_HOME_FLAKE missing reference part")),
-      };
-      let attribute = elems
-        .next()
-        .map(crate::installable::parse_attribute)
-        .unwrap_or_default();
-
-      Installable::Flake {
-        reference,
-        attribute,
-      }
+      Installable::from_flake_str(&home_flake)
+        .wrap_err("Invalid flake reference in NH_HOME_FLAKE")?
     } else {
       self.installable
     };
@@ -397,6 +526,7 @@ impl HomeReplArgs {
       false,
       &self.extra_args,
       self.configuration.clone(),
+      None,
     )?;
 
     Command::new("nix")
@@ -409,3 +539,48 @@ impl HomeReplArgs {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::process;
+
+  use super::*;
+
+  fn run_script(script: &str, home: &std::path::Path) -> String {
+    let output = process::Command::new("sh")
+      .args(["-c", script])
+      .env("HOME", home)
+      .env("USER", "nh-test-no-such-user")
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+  }
+
+  #[test]
+  fn test_remote_scripts_find_the_current_generation() {
+    let home = tempfile::tempdir().unwrap();
+    assert_eq!(run_script(REMOTE_GENERATION_SCRIPT, home.path()), "");
+    assert_eq!(run_script(REMOTE_SPECIALISATION_SCRIPT, home.path()), "");
+
+    let generation = home.path().join("generation");
+    let profiles = home.path().join(".local/state/nix/profiles");
+    std::fs::create_dir_all(&generation).unwrap();
+    std::fs::create_dir_all(&profiles).unwrap();
+    std::os::unix::fs::symlink(&generation, profiles.join("home-manager"))
+      .unwrap();
+
+    let share = home.path().join(".local/share/home-manager");
+    std::fs::create_dir_all(&share).unwrap();
+    std::fs::write(share.join("specialisation"), "work\n").unwrap();
+
+    assert_eq!(
+      run_script(REMOTE_GENERATION_SCRIPT, home.path()),
+      generation.canonicalize().unwrap().display().to_string()
+    );
+    assert_eq!(
+      run_script(REMOTE_SPECIALISATION_SCRIPT, home.path()),
+      "work"
+    );
+  }
+}
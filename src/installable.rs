@@ -2,14 +2,19 @@ use std::{env, fs, path::PathBuf};
 
 use clap::{Arg, ArgAction, Args, FromArgMatches, error::ErrorKind};
 use color_eyre::owo_colors::OwoColorize;
+use thiserror::Error;
 use tracing::debug;
 
+pub mod flake_ref;
+
+pub use flake_ref::{FlakeRef, FlakeRefError, FlakeRefKind};
+
 // Reference: https://nix.dev/manual/nix/2.18/command-ref/new-cli/nix
 
 #[derive(Debug, Clone)]
 pub enum Installable {
   Flake {
-    reference: String,
+    reference: FlakeRef,
     attribute: Vec<String>,
   },
   File {
@@ -48,48 +53,51 @@ impl FromArgMatches for Installable {
       }
     }
 
+    let invalid_attribute = |e: AttributeError| {
+      clap::Error::raw(
+        ErrorKind::ValueValidation,
+        format!("Invalid attribute path: {e}\n"),
+      )
+    };
+
     if let Some(f) = file {
       return Ok(Self::File {
         path:      PathBuf::from(f),
-        attribute: parse_attribute(installable.cloned().unwrap_or_default()),
+        attribute: parse_attribute(installable.cloned().unwrap_or_default())
+          .map_err(invalid_attribute)?,
       });
     }
 
     if let Some(e) = expr {
       return Ok(Self::Expression {
         expression: e.to_string(),
-        attribute:  parse_attribute(installable.cloned().unwrap_or_default()),
+        attribute:  parse_attribute(installable.cloned().unwrap_or_default())
+          .map_err(invalid_attribute)?,
       });
     }
 
     if let Some(i) = installable {
-      let mut elems = i.splitn(2, '#');
-      let reference = elems.next().unwrap().to_owned();
-      return Ok(Self::Flake {
-        reference,
-        attribute: parse_attribute(
-          elems
-            .next()
-            .map(std::string::ToString::to_string)
-            .unwrap_or_default(),
-        ),
+      return Self::from_flake_str(i).map_err(|e| {
+        clap::Error::raw(
+          ErrorKind::ValueValidation,
+          format!("Invalid installable '{i}': {e}\n"),
+        )
       });
     }
 
     // Env var parsing & fallbacks
-    fn parse_flake_env(var: &str) -> Option<Installable> {
-      env::var(var).ok().map(|f| {
-        let mut elems = f.splitn(2, '#');
-        Installable::Flake {
-          reference: elems.next().unwrap().to_owned(),
-          attribute: parse_attribute(
-            elems
-              .next()
-              .map(std::string::ToString::to_string)
-              .unwrap_or_default(),
-          ),
-        }
-      })
+    fn parse_flake_env(var: &str) -> Result<Option<Installable>, clap::Error> {
+      env::var(var)
+        .ok()
+        .map(|f| {
+          Installable::from_flake_str(&f).map_err(|e| {
+            clap::Error::raw(
+              ErrorKind::ValueValidation,
+              format!("Invalid flake in {var}='{f}': {e}\n"),
+            )
+          })
+        })
+        .transpose()
     }
 
     // Command-specific flake env vars
@@ -103,7 +111,7 @@ impl FromArgMatches for Installable {
       };
 
       if !env_var.is_empty() {
-        if let Some(installable) = parse_flake_env(env_var) {
+        if let Some(installable) = parse_flake_env(env_var)? {
           return Ok(installable);
         }
       }
@@ -116,7 +124,7 @@ impl FromArgMatches for Installable {
       "NH_HOME_FLAKE",
       "NH_DARWIN_FLAKE",
     ] {
-      if let Some(installable) = parse_flake_env(var) {
+      if let Some(installable) = parse_flake_env(var)? {
         return Ok(installable);
       }
     }
@@ -124,7 +132,8 @@ impl FromArgMatches for Installable {
     if let Ok(f) = env::var("NH_FILE") {
       return Ok(Self::File {
         path:      PathBuf::from(f),
-        attribute: parse_attribute(env::var("NH_ATTRP").unwrap_or_default()),
+        attribute: parse_attribute(env::var("NH_ATTRP").unwrap_or_default())
+          .map_err(invalid_attribute)?,
       });
     }
 
@@ -203,9 +212,43 @@ Nix accepts various kinds of installables:
   }
 }
 
-// TODO: should handle quoted attributes, like foo."bar.baz" -> ["foo",
-// "bar.baz"] maybe use chumsky?
-pub fn parse_attribute<S>(s: S) -> Vec<String>
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AttributeError {
+  #[error("unterminated quote in attribute path '{0}'")]
+  UnterminatedQuote(String),
+
+  #[error("empty attribute name at position {position} in '{path}'")]
+  EmptyName { path: String, position: usize },
+
+  #[error("unexpected '{ch}' at position {position} in '{path}'")]
+  UnexpectedCharacter {
+    path:     String,
+    ch:       char,
+    position: usize,
+  },
+
+  #[error(
+    "string interpolation is not supported in attribute path '{0}', escape it \
+     as \\${{ inside a quoted name"
+  )]
+  Interpolation(String),
+}
+
+#[derive(Debug, Error)]
+pub enum InstallableError {
+  #[error(transparent)]
+  FlakeRef(#[from] FlakeRefError),
+
+  #[error(transparent)]
+  Attribute(#[from] AttributeError),
+}
+
+/// Parses an attribute path like `foo."bar.baz".qux` into its names.
+///
+/// Names containing dots or quotes are written as Nix strings, which may use
+/// the escapes `\"`, `\\`, `\$`, `\n`, `\r` and `\t`. Interpolation with
+/// `${...}` cannot be evaluated here and is rejected.
+pub fn parse_attribute<S>(s: S) -> Result<Vec<String>, AttributeError>
 where
   S: AsRef<str>,
 {
@@ -213,45 +256,147 @@ where
   let mut res = Vec::new();
 
   if s.is_empty() {
-    return res;
+    return Ok(res);
   }
 
-  let mut in_quote = false;
-
-  let mut elem = String::new();
-  for char in s.chars() {
-    match char {
-      '.' => {
-        if in_quote {
-          elem.push(char);
-        } else {
-          res.push(elem.clone());
-          elem = String::new();
+  let mut chars = s.char_indices().peekable();
+
+  loop {
+    let start = chars.peek().map_or(s.len(), |&(i, _)| i);
+    let mut elem = String::new();
+
+    if chars.next_if(|&(_, c)| c == '"').is_some() {
+      loop {
+        match chars.next() {
+          None => return Err(AttributeError::UnterminatedQuote(s.to_string())),
+          Some((_, '"')) => break,
+          Some((_, '\\')) => {
+            match chars.next() {
+              Some((_, 'n')) => elem.push('\n'),
+              Some((_, 'r')) => elem.push('\r'),
+              Some((_, 't')) => elem.push('\t'),
+              Some((_, c)) => elem.push(c),
+              None => {
+                return Err(AttributeError::UnterminatedQuote(s.to_string()));
+              },
+            }
+          },
+          Some((_, '$')) if chars.peek().is_some_and(|&(_, c)| c == '{') => {
+            return Err(AttributeError::Interpolation(s.to_string()));
+          },
+          Some((_, c)) => elem.push(c),
         }
-      },
-      '"' => {
-        in_quote = !in_quote;
-      },
-      _ => elem.push(char),
+      }
+
+      match chars.next() {
+        None => {
+          res.push(elem);
+          return Ok(res);
+        },
+        Some((_, '.')) => res.push(elem),
+        Some((position, ch)) => {
+          return Err(AttributeError::UnexpectedCharacter {
+            path: s.to_string(),
+            ch,
+            position,
+          });
+        },
+      }
+    } else {
+      loop {
+        match chars.next() {
+          None | Some((_, '.')) if elem.is_empty() => {
+            return Err(AttributeError::EmptyName {
+              path:     s.to_string(),
+              position: start,
+            });
+          },
+          None => {
+            res.push(elem);
+            return Ok(res);
+          },
+          Some((_, '.')) => {
+            res.push(elem);
+            break;
+          },
+          Some((position, '"')) => {
+            return Err(AttributeError::UnexpectedCharacter {
+              path: s.to_string(),
+              ch: '"',
+              position,
+            });
+          },
+          Some((_, '$')) if chars.peek().is_some_and(|&(_, c)| c == '{') => {
+            return Err(AttributeError::Interpolation(s.to_string()));
+          },
+          Some((_, c)) => elem.push(c),
+        }
+      }
     }
   }
-
-  res.push(elem);
-
-  assert!(!in_quote, "Failed to parse attribute: {s}");
-
-  res
 }
 
 #[test]
 fn test_parse_attribute() {
-  assert_eq!(parse_attribute(r"foo.bar"), vec!["foo", "bar"]);
-  assert_eq!(parse_attribute(r#"foo."bar.baz""#), vec!["foo", "bar.baz"]);
+  assert_eq!(parse_attribute(r"foo.bar").unwrap(), vec!["foo", "bar"]);
+  assert_eq!(parse_attribute(r#"foo."bar.baz""#).unwrap(), vec![
+    "foo", "bar.baz"
+  ]);
+  assert_eq!(parse_attribute(r#""a\"b".c"#).unwrap(), vec![r#"a"b"#, "c"]);
+  assert_eq!(parse_attribute(r#""\${x}\\""#).unwrap(), vec![r"${x}\"]);
+  assert_eq!(parse_attribute(r#""""#).unwrap(), vec![""]);
   let v: Vec<String> = vec![];
-  assert_eq!(parse_attribute(""), v);
+  assert_eq!(parse_attribute("").unwrap(), v);
+}
+
+#[test]
+fn test_parse_attribute_errors() {
+  assert!(matches!(
+    parse_attribute(r#"foo."bar"#),
+    Err(AttributeError::UnterminatedQuote(_))
+  ));
+  assert!(matches!(
+    parse_attribute("foo..bar"),
+    Err(AttributeError::EmptyName { position: 4, .. })
+  ));
+  assert!(matches!(
+    parse_attribute("foo."),
+    Err(AttributeError::EmptyName { .. })
+  ));
+  assert!(matches!(
+    parse_attribute(r#""foo"bar"#),
+    Err(AttributeError::UnexpectedCharacter { ch: 'b', .. })
+  ));
+  assert!(matches!(
+    parse_attribute(r#"foo"bar""#),
+    Err(AttributeError::UnexpectedCharacter { ch: '"', .. })
+  ));
+  assert!(matches!(
+    parse_attribute(r#"foo."${bar}""#),
+    Err(AttributeError::Interpolation(_))
+  ));
+  assert!(matches!(
+    parse_attribute("foo.${bar}"),
+    Err(AttributeError::Interpolation(_))
+  ));
 }
 
 impl Installable {
+  /// Parses a `FLAKEREF[#ATTRPATH]` installable, as accepted on the command
+  /// line and in the `NH_*FLAKE` environment variables.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the flake reference or the attribute path is
+  /// malformed.
+  pub fn from_flake_str(s: &str) -> Result<Self, InstallableError> {
+    let (reference, attribute) = s.split_once('#').unwrap_or((s, ""));
+    Ok(Self::Flake {
+      reference: reference.parse()?,
+      attribute: parse_attribute(attribute)?,
+    })
+  }
+
   #[must_use]
   pub fn to_args(&self) -> Vec<String> {
     let mut res = Vec::new();
@@ -286,7 +431,7 @@ impl Installable {
 fn test_installable_to_args() {
   assert_eq!(
     (Installable::Flake {
-      reference: String::from("w"),
+      reference: "w".parse().unwrap(),
       attribute: ["x", "y.z"].into_iter().map(str::to_string).collect(),
     })
     .to_args(),
@@ -319,8 +464,21 @@ where
 
     let s = elem.as_ref();
 
-    if s.contains('.') {
-      res.push_str(&format!(r#""{s}""#));
+    if s.is_empty() || s.contains(['.', '"']) || s.contains("${") {
+      res.push('"');
+      let mut chars = s.chars().peekable();
+      while let Some(c) = chars.next() {
+        match c {
+          '"' => res.push_str(r#"\""#),
+          '\\' => res.push_str(r"\\"),
+          '\n' => res.push_str(r"\n"),
+          '\r' => res.push_str(r"\r"),
+          '\t' => res.push_str(r"\t"),
+          '$' if chars.peek() == Some(&'{') => res.push_str(r"\$"),
+          c => res.push(c),
+        }
+      }
+      res.push('"');
     } else {
       res.push_str(s);
     }
@@ -329,10 +487,29 @@ where
   res
 }
 
+#[test]
+fn test_installable_from_flake_str() {
+  let Installable::Flake {
+    reference,
+    attribute,
+  } = Installable::from_flake_str(r#"github:foo/bar?dir=x#a."b.c""#).unwrap()
+  else {
+    panic!("Expected a flake installable");
+  };
+  assert_eq!(reference.as_str(), "github:foo/bar?dir=x");
+  assert_eq!(reference.dir(), Some("x"));
+  assert_eq!(attribute, vec!["a", "b.c"]);
+
+  assert!(Installable::from_flake_str("#foo").is_err());
+}
+
 #[test]
 fn test_join_attribute() {
   assert_eq!(join_attribute(vec!["foo", "bar"]), "foo.bar");
   assert_eq!(join_attribute(vec!["foo", "bar.baz"]), r#"foo."bar.baz""#);
+  assert_eq!(join_attribute(vec![r#"a"b.c"#, ""]), r#""a\"b.c"."""#);
+  assert_eq!(join_attribute(vec![r"${x}\"]), r#""\${x}\\""#);
+  assert_eq!(join_attribute(vec![r"a\b", "$x"]), r"a\b.$x");
 }
 
 impl Installable {
@@ -346,3 +523,25 @@ impl Installable {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use proptest::prelude::*;
+
+  use super::*;
+
+  proptest! {
+      #[test]
+      fn test_attribute_round_trip(
+          attribute in prop::collection::vec(".*", 0..5)
+      ) {
+          let joined = join_attribute(&attribute);
+          prop_assert_eq!(parse_attribute(&joined), Ok(attribute));
+      }
+
+      #[test]
+      fn test_parse_attribute_never_panics(s in ".*") {
+          let _ = parse_attribute(&s);
+      }
+  }
+}
@@ -0,0 +1,518 @@
+use std::{
+  fmt,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use thiserror::Error;
+
+// Reference: https://nix.dev/manual/nix/2.28/command-ref/new-cli/nix3-flake#flake-references
+
+/// A parsed flake reference, such as `github:NixOS/nixpkgs/nixos-unstable`,
+/// `git+https://example.org/repo?ref=main` or `/etc/nixos`.
+///
+/// The original string is kept around and is what gets passed to Nix, so
+/// parsing never changes the meaning of a reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakeRef {
+  raw:    String,
+  kind:   FlakeRefKind,
+  params: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlakeRefKind {
+  /// A plain path or a `path:` URL
+  Path { path: PathBuf },
+
+  /// A git repository, like `git+https://...` or `git+file:///...`
+  Git {
+    /// The transport after `git+`, e.g. `https`, `ssh` or `file`
+    transport: String,
+    /// The repository URL without the `git+` prefix and the query
+    url:       String,
+  },
+
+  /// A repository on a git forge, like `github:owner/repo/ref`
+  Forge {
+    forge:      Forge,
+    owner:      String,
+    repo:       String,
+    ref_or_rev: Option<String>,
+  },
+
+  /// A tarball or a plain file fetched over the network
+  Tarball { url: String },
+
+  /// A mercurial repository, like `hg+https://...`
+  Mercurial { url: String },
+
+  /// A reference resolved through the flake registry, like `nixpkgs`
+  Indirect {
+    id:         String,
+    ref_or_rev: Option<String>,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forge {
+  GitHub,
+  GitLab,
+  SourceHut,
+}
+
+impl Forge {
+  #[must_use]
+  pub const fn scheme(self) -> &'static str {
+    match self {
+      Self::GitHub => "github",
+      Self::GitLab => "gitlab",
+      Self::SourceHut => "sourcehut",
+    }
+  }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FlakeRefError {
+  #[error("flake reference is empty")]
+  Empty,
+
+  #[error("unsupported flake reference type '{scheme}:' in '{reference}'")]
+  UnsupportedScheme {
+    reference: String,
+    scheme:    String,
+  },
+
+  #[error(
+    "'{reference}' must be of the form {scheme}:<owner>/<repo>[/<ref-or-rev>]"
+  )]
+  InvalidForge {
+    reference: String,
+    scheme:    &'static str,
+  },
+
+  #[error("'{reference}' has an empty URL")]
+  EmptyUrl { reference: String },
+
+  #[error(
+    "'{0}' is neither a path nor a flake registry identifier. Paths must \
+     start with '/' or '.', e.g. './{0}'"
+  )]
+  InvalidIndirect(String),
+
+  #[error("malformed parameter '{param}' in '{reference}', expected key=value")]
+  InvalidParameter {
+    reference: String,
+    param:     String,
+  },
+}
+
+impl FlakeRef {
+  #[must_use]
+  pub fn as_str(&self) -> &str {
+    &self.raw
+  }
+
+  #[must_use]
+  pub const fn kind(&self) -> &FlakeRefKind {
+    &self.kind
+  }
+
+  /// Returns the value of the `?key=value` parameter named `key`.
+  #[must_use]
+  pub fn param(&self, key: &str) -> Option<&str> {
+    self
+      .params
+      .iter()
+      .find(|(k, _)| k == key)
+      .map(|(_, v)| v.as_str())
+  }
+
+  /// Subdirectory of the source tree containing `flake.nix`, from `?dir=`.
+  #[must_use]
+  pub fn dir(&self) -> Option<&str> {
+    self.param("dir")
+  }
+
+  /// Whether the flake lives on the local filesystem, i.e. it is a path or a
+  /// `git+file` repository.
+  #[must_use]
+  pub fn is_local(&self) -> bool {
+    self.local_path().is_some()
+  }
+
+  /// Whether the flake is a git repository on the local filesystem. Plain
+  /// paths count too, since Nix treats them as git repositories when they are
+  /// inside one.
+  #[must_use]
+  pub fn is_local_git(&self) -> bool {
+    match &self.kind {
+      FlakeRefKind::Git { transport, .. } => transport == "file",
+      FlakeRefKind::Path { .. } => !self.raw.starts_with("path:"),
+      _ => false,
+    }
+  }
+
+  /// The local source tree of the flake, if it lives on the local
+  /// filesystem. For `?dir=` references this is the root of the tree, see
+  /// [`FlakeRef::flake_dir`] for the directory containing `flake.nix`.
+  #[must_use]
+  pub fn local_path(&self) -> Option<&Path> {
+    match &self.kind {
+      FlakeRefKind::Path { path } => Some(path),
+      FlakeRefKind::Git { transport, url } if transport == "file" => {
+        Some(Path::new(strip_authority(
+          url.strip_prefix("file:").unwrap_or(url),
+        )))
+      },
+      _ => None,
+    }
+  }
+
+  /// The local directory containing `flake.nix`, honouring `?dir=`.
+  #[must_use]
+  pub fn flake_dir(&self) -> Option<PathBuf> {
+    let root = self.local_path()?;
+    Some(match self.dir() {
+      Some(dir) => root.join(dir),
+      None => root.to_path_buf(),
+    })
+  }
+}
+
+impl fmt::Display for FlakeRef {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.raw)
+  }
+}
+
+impl AsRef<str> for FlakeRef {
+  fn as_ref(&self) -> &str {
+    &self.raw
+  }
+}
+
+impl FromStr for FlakeRef {
+  type Err = FlakeRefError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.is_empty() {
+      return Err(FlakeRefError::Empty);
+    }
+
+    let (base, query) = match s.split_once('?') {
+      Some((base, query)) => (base, Some(query)),
+      None => (s, None),
+    };
+
+    let params = match query {
+      Some(query) => parse_params(s, query)?,
+      None => Vec::new(),
+    };
+
+    let kind = match split_scheme(base) {
+      Some((scheme, rest)) => parse_url(s, scheme, rest)?,
+      None if base.starts_with('/') || base.starts_with('.') => {
+        FlakeRefKind::Path {
+          path: PathBuf::from(base),
+        }
+      },
+      None => {
+        parse_indirect(base)
+          .ok_or_else(|| FlakeRefError::InvalidIndirect(base.to_string()))?
+      },
+    };
+
+    Ok(Self {
+      raw: s.to_string(),
+      kind,
+      params,
+    })
+  }
+}
+
+/// Splits `scheme:rest`, where the scheme is made of the characters allowed
+/// in URL schemes. Returns `None` for strings without a scheme.
+fn split_scheme(s: &str) -> Option<(&str, &str)> {
+  let (scheme, rest) = s.split_once(':')?;
+  let valid = scheme
+    .chars()
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic())
+    && scheme
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+  valid.then_some((scheme, rest))
+}
+
+fn parse_url(
+  reference: &str,
+  scheme: &str,
+  rest: &str,
+) -> Result<FlakeRefKind, FlakeRefError> {
+  let non_empty = |url: String| {
+    if url.is_empty() || url.ends_with(':') {
+      Err(FlakeRefError::EmptyUrl {
+        reference: reference.to_string(),
+      })
+    } else {
+      Ok(url)
+    }
+  };
+
+  let kind = match scheme {
+    "path" => {
+      FlakeRefKind::Path {
+        path: PathBuf::from(non_empty(strip_authority(rest).to_string())?),
+      }
+    },
+    "flake" => {
+      parse_indirect(rest)
+        .ok_or_else(|| FlakeRefError::InvalidIndirect(rest.to_string()))?
+    },
+    "github" | "gitlab" | "sourcehut" => {
+      let forge = match scheme {
+        "github" => Forge::GitHub,
+        "gitlab" => Forge::GitLab,
+        _ => Forge::SourceHut,
+      };
+      parse_forge(reference, forge, rest)?
+    },
+    "git" => {
+      FlakeRefKind::Git {
+        transport: String::from("git"),
+        url:       non_empty(format!("git:{rest}"))?,
+      }
+    },
+    "http" | "https" | "file" => {
+      FlakeRefKind::Tarball {
+        url: non_empty(format!("{scheme}:{rest}"))?,
+      }
+    },
+    _ => {
+      if let Some(transport) = scheme.strip_prefix("git+") {
+        FlakeRefKind::Git {
+          transport: transport.to_string(),
+          url:       non_empty(format!("{transport}:{rest}"))?,
+        }
+      } else if let Some(transport) = scheme.strip_prefix("hg+") {
+        FlakeRefKind::Mercurial {
+          url: non_empty(format!("{transport}:{rest}"))?,
+        }
+      } else if let Some(transport) = scheme
+        .strip_prefix("tarball+")
+        .or_else(|| scheme.strip_prefix("file+"))
+      {
+        FlakeRefKind::Tarball {
+          url: non_empty(format!("{transport}:{rest}"))?,
+        }
+      } else {
+        return Err(FlakeRefError::UnsupportedScheme {
+          reference: reference.to_string(),
+          scheme:    scheme.to_string(),
+        });
+      }
+    },
+  };
+
+  Ok(kind)
+}
+
+fn parse_forge(
+  reference: &str,
+  forge: Forge,
+  rest: &str,
+) -> Result<FlakeRefKind, FlakeRefError> {
+  let invalid = || {
+    FlakeRefError::InvalidForge {
+      reference: reference.to_string(),
+      scheme:    forge.scheme(),
+    }
+  };
+
+  let mut parts = rest.splitn(3, '/');
+  let owner = parts.next().filter(|s| !s.is_empty()).ok_or_else(invalid)?;
+  let repo = parts.next().filter(|s| !s.is_empty()).ok_or_else(invalid)?;
+  let ref_or_rev = match parts.next() {
+    Some("") => return Err(invalid()),
+    other => other.map(String::from),
+  };
+
+  Ok(FlakeRefKind::Forge {
+    forge,
+    owner: owner.to_string(),
+    repo: repo.to_string(),
+    ref_or_rev,
+  })
+}
+
+/// Parses `id[/ref-or-rev]`, as used by flake registry entries.
+fn parse_indirect(s: &str) -> Option<FlakeRefKind> {
+  let mut parts = s.splitn(2, '/');
+  let id = parts.next()?;
+
+  let valid_id = id.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+  if !valid_id {
+    return None;
+  }
+
+  let ref_or_rev = match parts.next() {
+    Some("") => return None,
+    other => other.map(String::from),
+  };
+
+  Some(FlakeRefKind::Indirect {
+    id: id.to_string(),
+    ref_or_rev,
+  })
+}
+
+fn parse_params(
+  reference: &str,
+  query: &str,
+) -> Result<Vec<(String, String)>, FlakeRefError> {
+  query
+    .split('&')
+    .filter(|param| !param.is_empty())
+    .map(|param| {
+      match param.split_once('=') {
+        Some((key, value)) if !key.is_empty() => {
+          Ok((key.to_string(), value.to_string()))
+        },
+        _ => {
+          Err(FlakeRefError::InvalidParameter {
+            reference: reference.to_string(),
+            param:     param.to_string(),
+          })
+        },
+      }
+    })
+    .collect()
+}
+
+/// Strips an empty URL authority, turning `///foo` into `/foo`.
+fn strip_authority(s: &str) -> &str {
+  s.strip_prefix("//").unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(s: &str) -> FlakeRef {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn test_parse_paths() {
+    for s in [".", "./flake", "/etc/nixos", "path:/etc/nixos"] {
+      let flake = parse(s);
+      assert!(matches!(flake.kind(), FlakeRefKind::Path { .. }), "{s}");
+      assert!(flake.is_local(), "{s}");
+    }
+
+    assert_eq!(
+      parse("path:///etc/nixos").local_path(),
+      Some(Path::new("/etc/nixos"))
+    );
+    assert!(parse("/etc/nixos").is_local_git());
+    assert!(!parse("path:/etc/nixos").is_local_git());
+  }
+
+  #[test]
+  fn test_parse_git() {
+    let flake = parse("git+https://example.org/repo.git?ref=main&dir=hosts");
+    assert_eq!(flake.kind(), &FlakeRefKind::Git {
+      transport: String::from("https"),
+      url:       String::from("https://example.org/repo.git"),
+    });
+    assert_eq!(flake.param("ref"), Some("main"));
+    assert_eq!(flake.dir(), Some("hosts"));
+    assert!(!flake.is_local());
+
+    let flake = parse("git+file:///home/user/config?dir=nix");
+    assert_eq!(flake.local_path(), Some(Path::new("/home/user/config")));
+    assert_eq!(
+      flake.flake_dir(),
+      Some(PathBuf::from("/home/user/config/nix"))
+    );
+    assert!(flake.is_local_git());
+  }
+
+  #[test]
+  fn test_parse_forge() {
+    let flake = parse("github:NixOS/nixpkgs/nixos-unstable");
+    assert_eq!(flake.kind(), &FlakeRefKind::Forge {
+      forge:      Forge::GitHub,
+      owner:      String::from("NixOS"),
+      repo:       String::from("nixpkgs"),
+      ref_or_rev: Some(String::from("nixos-unstable")),
+    });
+
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+    let flake = parse(&format!("gitlab:owner/repo/{rev}"));
+    assert_eq!(flake.kind(), &FlakeRefKind::Forge {
+      forge:      Forge::GitLab,
+      owner:      String::from("owner"),
+      repo:       String::from("repo"),
+      ref_or_rev: Some(String::from(rev)),
+    });
+
+    assert!(matches!(
+      "github:NixOS".parse::<FlakeRef>(),
+      Err(FlakeRefError::InvalidForge { .. })
+    ));
+  }
+
+  #[test]
+  fn test_parse_indirect() {
+    assert_eq!(parse("nixpkgs").kind(), &FlakeRefKind::Indirect {
+      id:         String::from("nixpkgs"),
+      ref_or_rev: None,
+    });
+    assert_eq!(
+      parse("flake:nixpkgs/nixos-24.05").kind(),
+      &FlakeRefKind::Indirect {
+        id:         String::from("nixpkgs"),
+        ref_or_rev: Some(String::from("nixos-24.05")),
+      }
+    );
+    assert!(matches!(
+      "my flake".parse::<FlakeRef>(),
+      Err(FlakeRefError::InvalidIndirect(_))
+    ));
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert_eq!("".parse::<FlakeRef>(), Err(FlakeRefError::Empty));
+    assert!(matches!(
+      "foo+bar:baz".parse::<FlakeRef>(),
+      Err(FlakeRefError::UnsupportedScheme { .. })
+    ));
+    assert!(matches!(
+      "github:a/b?dir".parse::<FlakeRef>(),
+      Err(FlakeRefError::InvalidParameter { .. })
+    ));
+    assert!(matches!(
+      "git+https:".parse::<FlakeRef>(),
+      Err(FlakeRefError::EmptyUrl { .. })
+    ));
+  }
+
+  #[test]
+  fn test_display_is_unchanged() {
+    for s in [
+      ".",
+      "github:NixOS/nixpkgs?dir=lib",
+      "git+ssh://git@example.org/repo",
+      "https://example.org/flake.tar.gz",
+      "hg+https://example.org/repo",
+    ] {
+      assert_eq!(parse(s).to_string(), s);
+    }
+  }
+}
@@ -1,4 +1,4 @@
-use std::{env, path::PathBuf};
+use std::{env, num::NonZeroUsize, path::PathBuf};
 
 use anstyle::Style;
 use clap::{Args, Parser, Subcommand, ValueEnum, builder::Styles};
@@ -16,7 +16,9 @@ use crate::{
     OsReplFeatures,
   },
   commands::ElevationStrategy,
+  deploy::DeployTarget,
   installable::Installable,
+  ssh::SshTarget,
 };
 
 const fn make_style() -> Styles {
@@ -63,7 +65,9 @@ pub enum NHCommand {
   Os(OsArgs),
   Home(HomeArgs),
   Darwin(DarwinArgs),
+  Flake(FlakeArgs),
   Search(SearchArgs),
+  Which(WhichArgs),
   Clean(CleanProxy),
   #[command(hide = true)]
   Completions(CompletionArgs),
@@ -76,7 +80,9 @@ impl NHCommand {
       Self::Os(args) => args.get_feature_requirements(),
       Self::Home(args) => args.get_feature_requirements(),
       Self::Darwin(args) => args.get_feature_requirements(),
+      Self::Flake(_) => Box::new(FlakeFeatures),
       Self::Search(_) => Box::new(NoFeatures),
+      Self::Which(_) => Box::new(NoFeatures),
       Self::Clean(_) => Box::new(NoFeatures),
       Self::Completions(_) => Box::new(NoFeatures),
     }
@@ -94,7 +100,9 @@ impl NHCommand {
         }
         args.run(elevation)
       },
+      Self::Flake(args) => args.run(),
       Self::Search(args) => args.run(),
+      Self::Which(args) => args.run(),
       Self::Clean(proxy) => proxy.command.run(elevation),
       Self::Completions(args) => args.run(),
       Self::Home(args) => {
@@ -221,13 +229,67 @@ pub struct OsRebuildArgs {
   #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
   pub bypass_root_check: bool,
 
-  /// Deploy the configuration to a different host over ssh
+  /// Deploy the configuration to a different host over ssh, given as
+  /// `[user@]host[:port]` or as an ssh:// or ssh-ng:// URI. NIX_SSHOPTS
+  /// applies to every connection.
+  ///
+  /// Repeat to deploy to several hosts in parallel. Each of them gets the
+  /// configuration named like its short host name, or NAME when given as
+  /// `NAME=TARGET`
   #[arg(long)]
-  pub target_host: Option<String>,
+  pub target_host: Vec<DeployTarget>,
+
+  /// Deploy to every host of a JSON file mapping nixosConfigurations names to
+  /// ssh targets, like `{"web": "root@web.example.com"}`
+  #[arg(long, conflicts_with = "target_host")]
+  pub inventory: Option<PathBuf>,
+
+  /// Number of hosts to copy to and activate at the same time when deploying
+  /// to several hosts
+  #[arg(long, default_value = "4")]
+  pub parallel: NonZeroUsize,
+
+  /// Build the configuration on a different host over ssh, given like
+  /// --target-host
+  #[arg(long)]
+  pub build_host: Option<SshTarget>,
+
+  #[command(flatten)]
+  pub copy: CopyArgs,
 
-  /// Build the configuration to a different host over ssh
+  /// After switching or testing on --target-host, reconnect to it over a new
+  /// ssh connection. If that fails within --rollback-timeout, or activation
+  /// fails, the host activates its previous generation again on its own
   #[arg(long)]
-  pub build_host: Option<String>,
+  pub magic_rollback: bool,
+
+  /// How long the target host waits for nh to reconnect before rolling back
+  #[arg(long, default_value = "90s", value_name = "DURATION")]
+  pub rollback_timeout: humantime::Duration,
+
+  /// Refuse to switch or boot from a local git flake with uncommitted
+  /// changes
+  #[arg(long, env = "NH_REQUIRE_CLEAN")]
+  pub require_clean: bool,
+
+  /// Like --require-clean, but only for the configurations of these
+  /// comma-separated hostnames
+  #[arg(
+    long,
+    env = "NH_REQUIRE_CLEAN_HOSTS",
+    value_delimiter = ',',
+    value_name = "HOSTNAMES"
+  )]
+  pub require_clean_hosts: Vec<String>,
+
+  /// Switch or boot even though --require-clean applies and the tree is dirty
+  #[arg(long)]
+  pub allow_dirty: bool,
+
+  /// Don't ask for the sudo password before building and keep it fresh until
+  /// activation, only ask when it is needed
+  #[arg(long, env = "NH_NO_SUDO_KEEPALIVE")]
+  pub no_sudo_keepalive: bool,
 }
 
 impl OsRebuildArgs {
@@ -241,6 +303,17 @@ impl OsRebuildArgs {
     // Check installable type
     matches!(self.common.installable, Installable::Flake { .. })
   }
+
+  /// Returns whether the configuration of `hostname` must be built from a
+  /// clean git tree.
+  #[must_use]
+  pub fn requires_clean(&self, hostname: &str) -> bool {
+    self.require_clean
+      || self
+        .require_clean_hosts
+        .iter()
+        .any(|host| host.trim() == hostname)
+  }
 }
 
 #[derive(ValueEnum, Clone, Default, Debug)]
@@ -312,6 +385,11 @@ pub struct CommonRebuildArgs {
   #[arg(long, short, value_enum, default_value_t = DiffType::Auto)]
   pub diff: DiffType,
 
+  /// Add untracked .nix files of a local git flake with `git add
+  /// --intent-to-add` before building, so that the flake can see them
+  #[arg(long, env = "NH_ADD_UNTRACKED")]
+  pub add_untracked: bool,
+
   #[command(flatten)]
   pub passthrough: NixBuildPassthroughArgs,
 }
@@ -375,6 +453,105 @@ pub struct SearchArgs {
   pub query: Vec<String>,
 }
 
+#[derive(Args, Debug)]
+/// Flake functionality
+pub struct FlakeArgs {
+  #[command(subcommand)]
+  pub subcommand: FlakeSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FlakeSubcommand {
+  /// Update flake inputs and show what changed in flake.lock
+  Update(FlakeUpdateArgs),
+
+  /// List flake inputs that have not been updated for a while
+  Status(FlakeStatusArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct FlakeUpdateArgs {
+  #[arg(long, short, env = "NH_FLAKE", default_value = ".")]
+  /// Flake whose inputs should be updated
+  pub flake: String,
+
+  #[arg(long, short)]
+  /// Output the changed inputs as JSON
+  pub json: bool,
+
+  #[arg(long, short, conflicts_with = "inputs")]
+  /// Choose the inputs to update from a list
+  pub interactive: bool,
+
+  /// Inputs to update, all inputs are updated if none are given. Nested
+  /// inputs can be given as a path, e.g. home-manager/nixpkgs
+  pub inputs: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+#[clap(verbatim_doc_comment)]
+/// List flake inputs that have not been updated for a while
+///
+/// For --max-age, see the documentation of humantime for possible formats: <https://docs.rs/humantime/latest/humantime/fn.parse_duration.html>
+pub struct FlakeStatusArgs {
+  #[arg(long, short, env = "NH_FLAKE", default_value = ".")]
+  /// Flake whose inputs should be checked
+  pub flake: String,
+
+  #[arg(long, short = 'a', env = "NH_FLAKE_MAX_AGE", default_value = "30d")]
+  /// Report inputs whose locked revision is older than this
+  pub max_age: humantime::Duration,
+
+  #[arg(long)]
+  /// Exit with an error if any input is too old
+  pub check: bool,
+
+  #[arg(long, short)]
+  /// Output the stale inputs as JSON
+  pub json: bool,
+}
+
+#[derive(Args, Debug)]
+/// Finds which package provides a program
+///
+/// Looks the program up in the programs.sqlite database shipped with the
+/// nixos channel and in the nix-index database, falling back to
+/// search.nixos.org when neither knows about it.
+///
+/// Candidates are ranked by name only: packages named exactly like the
+/// program come first, then top-level packages before nested ones. None of
+/// the sources has popularity data.
+pub struct WhichArgs {
+  #[arg(long, short, default_value = "10")]
+  /// Number of candidates to display
+  pub limit: u64,
+
+  #[arg(long, short, env = "NH_PROGRAMS_DB", value_hint = clap::ValueHint::FilePath)]
+  /// Path to a programs.sqlite database, defaults to the one from the root
+  /// user's nixos channel
+  pub database: Option<PathBuf>,
+
+  #[arg(
+    long,
+    short,
+    env = "NH_SEARCH_CHANNEL",
+    default_value = "nixos-unstable"
+  )]
+  /// Name of the channel to query when falling back to search.nixos.org
+  pub channel: String,
+
+  #[arg(long)]
+  /// Don't fall back to search.nixos.org
+  pub offline: bool,
+
+  #[arg(long, short = 'j', env = "NH_SEARCH_JSON", value_parser = clap::builder::BoolishValueParser::new())]
+  /// Output results as JSON
+  pub json: bool,
+
+  /// Name of the program to look up, e.g. rg
+  pub program: String,
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 pub enum SearchNixpkgsFrom {
   Flake,
@@ -514,6 +691,14 @@ pub struct HomeRebuildArgs {
   /// Move existing files by backing up with this file extension
   #[arg(long, short = 'b')]
   pub backup_extension: Option<String>,
+
+  /// Activate the configuration for a user on a different host over ssh,
+  /// given as `user@host[:port]` or as an ssh:// or ssh-ng:// URI
+  #[arg(long)]
+  pub target_host: Option<SshTarget>,
+
+  #[command(flatten)]
+  pub copy: CopyArgs,
 }
 
 impl HomeRebuildArgs {
@@ -623,6 +808,11 @@ pub struct DarwinRebuildArgs {
   /// Don't panic if calling nh as root
   #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
   pub bypass_root_check: bool,
+
+  /// Don't ask for the sudo password before building and keep it fresh until
+  /// activation, only ask when it is needed
+  #[arg(long, env = "NH_NO_SUDO_KEEPALIVE")]
+  pub no_sudo_keepalive: bool,
 }
 
 impl DarwinRebuildArgs {
@@ -662,15 +852,20 @@ impl DarwinReplArgs {
   }
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct UpdateArgs {
   #[arg(short = 'u', long = "update", conflicts_with = "update_input")]
-  /// Update all flake inputs
+  /// Update all flake inputs, or the channels of non-flake configurations
   pub update_all: bool,
 
   #[arg(short = 'U', long = "update-input", conflicts_with = "update_all")]
-  /// Update the specified flake input(s)
+  /// Update the specified flake input(s), or channel(s) of non-flake
+  /// configurations
   pub update_input: Option<Vec<String>>,
+
+  #[arg(long, env = "NH_KEEP_FAILED_UPDATE")]
+  /// Keep the updated flake.lock even if the build or activation fails
+  pub keep_failed_update: bool,
 }
 
 #[derive(Debug, Args)]
@@ -763,13 +958,36 @@ pub struct NixBuildPassthroughArgs {
   #[arg(long, short = 'Q')]
   pub no_build_output: bool,
 
-  /// Use substitutes when copying
+  /// Output results in JSON format
   #[arg(long)]
+  pub json: bool,
+}
+
+/// How store paths are copied to and from other hosts.
+#[derive(Debug, Clone, Default, Args)]
+pub struct CopyArgs {
+  /// Let the host that paths are copied to fetch them from its binary caches
+  /// when it can, instead of copying them from here
+  #[arg(long, alias = "substitute-on-destination")]
   pub use_substitutes: bool,
 
-  /// Output results in JSON format
+  /// Compress the paths copied to and from other hosts over ssh
   #[arg(long)]
-  pub json: bool,
+  pub compress: bool,
+
+  /// Set an option of the ssh store of other hosts, such as
+  /// `remote-program=nix-daemon` or `ssh-key=/root/.ssh/deploy`
+  #[arg(long = "store-option", value_name = "KEY=VALUE", value_parser = parse_store_option)]
+  pub store_options: Vec<String>,
+}
+
+fn parse_store_option(option: &str) -> Result<String, String> {
+  match option.split_once('=') {
+    Some((key, _)) if !key.is_empty() && !option.contains(['&', '?']) => {
+      Ok(option.to_string())
+    },
+    _ => Err(format!("expected KEY=VALUE, got '{option}'")),
+  }
 }
 
 impl NixBuildPassthroughArgs {
@@ -848,9 +1066,6 @@ impl NixBuildPassthroughArgs {
     if self.no_build_output {
       args.push("--no-build-output".into());
     }
-    if self.use_substitutes {
-      args.push("--use-substitutes".into());
-    }
     if self.json {
       args.push("--json".into());
     }
@@ -858,3 +1073,38 @@ impl NixBuildPassthroughArgs {
     args
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn os_switch(args: &[&str]) -> OsRebuildArgs {
+    let main =
+      Main::try_parse_from(["nh", "os", "switch", "."].iter().chain(args))
+        .unwrap();
+    match main.command {
+      NHCommand::Os(OsArgs {
+        subcommand: OsSubcommand::Switch(args),
+      }) => args,
+      other => panic!("parsed as {other:?}"),
+    }
+  }
+
+  #[test]
+  fn test_requires_clean() {
+    let args = os_switch(&["--require-clean"]);
+    assert!(args.requires_clean("web"));
+
+    let args = os_switch(&["--require-clean-hosts", "web,db"]);
+    assert!(args.requires_clean("db"));
+    assert!(!args.requires_clean("mail"));
+
+    // Hosts may have names that read like a boolean
+    let args = os_switch(&["--require-clean-hosts", "yes,on"]);
+    assert!(args.requires_clean("yes"));
+    assert!(args.requires_clean("on"));
+    assert!(!args.requires_clean("web"));
+
+    assert!(!os_switch(&[]).requires_clean("web"));
+  }
+}
@@ -0,0 +1,248 @@
+//! Handling of SIGINT, SIGTERM and SIGHUP.
+//!
+//! While a command runs, nh records the signal instead of exiting on the spot
+//! and returns an [`Interrupted`] error once the command has exited, so that
+//! the stack unwinds normally and temporary directories and `flake.lock`
+//! snapshots are cleaned up. Otherwise the signal has its default effect, as
+//! not every code path checks for it.
+//!
+//! Some steps must not be interrupted half way, such as setting the system
+//! profile and installing the bootloader. These run in a [`critical`]
+//! section: the commands they spawn ignore SIGINT, and the signal is only
+//! acted upon once the whole section has completed.
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+use color_eyre::eyre::Context;
+use nix::{
+  libc,
+  sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+  unistd::Pid,
+};
+use thiserror::Error;
+
+use crate::Result;
+
+const SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// The first signal received, or 0.
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+/// The process IDs of the commands currently running, with 0 for free slots.
+/// Commands run on several hosts at once when deploying to many of them.
+static CHILDREN: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+
+/// The number of critical sections running, on any thread.
+static CRITICAL: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of commands running, including those without a slot in
+/// [`CHILDREN`].
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// The program and arguments that run the command following them with
+/// SIGINT ignored. The disposition is set in the child alone, and an ignored
+/// signal stays ignored across exec.
+pub const IGNORE_SIGINT: [&str; 4] =
+  ["/bin/sh", "-c", "trap '' INT; exec \"$@\"", "sh"];
+
+/// Returned once the running command has exited after a signal was received.
+#[derive(Debug, Error)]
+#[error("Interrupted by {0}")]
+pub struct Interrupted(pub Signal);
+
+/// Installs the signal handlers. Until this is called signals have their
+/// default effect.
+pub fn install() -> Result<()> {
+  let action = SigAction::new(
+    SigHandler::SigAction(handle),
+    SaFlags::SA_SIGINFO | SaFlags::SA_RESTART,
+    SigSet::empty(),
+  );
+
+  for sig in SIGNALS {
+    // SAFETY: the handler only uses async-signal-safe functions
+    unsafe { signal::sigaction(sig, &action) }
+      .with_context(|| format!("Failed to install a handler for {sig}"))?;
+  }
+
+  Ok(())
+}
+
+// rustfmt would drop the "C" from the ABI, as force_explicit_abi is off
+#[rustfmt::skip]
+extern "C" fn handle(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+  on_signal(sig, info);
+}
+
+fn on_signal(sig: libc::c_int, info: *const libc::siginfo_t) {
+  let critical = CRITICAL.load(Ordering::SeqCst) > 0;
+  if !critical && RUNNING.load(Ordering::SeqCst) == 0 {
+    // Nothing would report the signal in time
+    exit_with(sig);
+    return;
+  }
+
+  let first = PENDING
+    .compare_exchange(0, sig, Ordering::SeqCst, Ordering::SeqCst)
+    .is_ok();
+
+  if critical {
+    notify(
+      b"\nnh: waiting for the current step to finish before exiting, the \
+        system would be left in an inconsistent state otherwise\n",
+    );
+    return;
+  }
+
+  if !first {
+    // A second signal exits right away
+    exit_with(sig);
+    return;
+  }
+
+  // Signals from the terminal already reach every process in the foreground
+  // process group, only forward the ones that were sent to nh alone
+  // SAFETY: the kernel passes a valid siginfo_t with SA_SIGINFO
+  let sent_by_process =
+    !info.is_null() && unsafe { (*info).si_code } == libc::SI_USER;
+  if !sent_by_process {
+    return;
+  }
+  for child in &CHILDREN {
+    let child = child.load(Ordering::SeqCst);
+    if child > 0 {
+      // SAFETY: kill is async-signal-safe
+      unsafe {
+        libc::kill(child, sig);
+      }
+    }
+  }
+}
+
+/// Takes the default action of `sig` from the signal handler.
+fn exit_with(sig: libc::c_int) {
+  // SAFETY: signal and raise are async-signal-safe
+  unsafe {
+    libc::signal(sig, libc::SIG_DFL);
+    libc::raise(sig);
+  }
+}
+
+/// Writes a message to stderr from the signal handler.
+fn notify(message: &[u8]) {
+  // SAFETY: write is async-signal-safe and the buffer is valid
+  unsafe {
+    libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+  }
+}
+
+/// Returns an error if a signal was received, unless a critical section is
+/// running.
+pub fn check() -> Result<(), Interrupted> {
+  interruption(is_critical(), PENDING.load(Ordering::SeqCst))
+}
+
+/// Returns whether a critical section is running, on any thread.
+pub fn is_critical() -> bool {
+  CRITICAL.load(Ordering::SeqCst) > 0
+}
+
+fn interruption(critical: bool, pending: i32) -> Result<(), Interrupted> {
+  match pending {
+    _ if critical => Ok(()),
+    0 => Ok(()),
+    sig => Err(Interrupted(Signal::try_from(sig).unwrap_or(Signal::SIGINT))),
+  }
+}
+
+/// Runs `f` in a critical section, during which signals are recorded but do
+/// not interrupt anything. [`check`] reports them once `f` returns, whether
+/// it succeeded or not.
+pub fn critical<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+  CRITICAL.fetch_add(1, Ordering::SeqCst);
+  let result = f();
+  CRITICAL.fetch_sub(1, Ordering::SeqCst);
+  result
+}
+
+/// Starts a command with `spawn` and forwards signals sent to nh to it until
+/// the returned guard is dropped. Until then, signals are recorded rather
+/// than acted upon.
+pub fn spawn<T, E>(
+  spawn: impl FnOnce() -> Result<T, E>,
+  pid: impl FnOnce(&T) -> Option<u32>,
+) -> Result<(T, ChildGuard)>
+where
+  E: std::error::Error + Send + Sync + 'static,
+{
+  // Counted before spawning, so that a signal cannot kill nh while the
+  // command starts
+  RUNNING.fetch_add(1, Ordering::SeqCst);
+  let child = spawn().inspect_err(|_| {
+    RUNNING.fetch_sub(1, Ordering::SeqCst);
+  })?;
+
+  let pid = pid(&child)
+    .and_then(|pid| i32::try_from(pid).ok())
+    .unwrap_or(0);
+  // Without a free slot the command still gets signals from the terminal
+  let slot = (pid > 0)
+    .then(|| {
+      CHILDREN.iter().position(|slot| {
+        slot
+          .compare_exchange(0, pid, Ordering::SeqCst, Ordering::SeqCst)
+          .is_ok()
+      })
+    })
+    .flatten();
+
+  Ok((child, ChildGuard {
+    slot,
+    pid: Pid::from_raw(pid),
+  }))
+}
+
+/// Stops forwarding signals to a command when dropped.
+#[derive(Debug)]
+pub struct ChildGuard {
+  slot: Option<usize>,
+  pid:  Pid,
+}
+
+impl Drop for ChildGuard {
+  fn drop(&mut self) {
+    RUNNING.fetch_sub(1, Ordering::SeqCst);
+    if let Some(slot) = self.slot {
+      let _ = CHILDREN[slot].compare_exchange(
+        self.pid.as_raw(),
+        0,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_interruption() {
+    assert!(interruption(false, 0).is_ok());
+    assert!(interruption(true, libc::SIGINT).is_ok());
+    assert!(matches!(
+      interruption(false, libc::SIGTERM),
+      Err(Interrupted(Signal::SIGTERM))
+    ));
+  }
+
+  #[test]
+  fn test_critical_ends_on_error() {
+    let result: Result<()> = critical(|| {
+      assert!(CRITICAL.load(Ordering::SeqCst) > 0);
+      Err(color_eyre::eyre::eyre!("boot failed"))
+    });
+    assert!(result.is_err());
+    assert_eq!(CRITICAL.load(Ordering::SeqCst), 0);
+  }
+}
@@ -4,16 +4,23 @@ pub mod clean;
 pub mod commands;
 pub mod completion;
 pub mod darwin;
+pub mod deploy;
+pub mod flake;
 pub mod generations;
+pub mod git;
 pub mod home;
 pub mod installable;
 pub mod interface;
+pub mod interrupt;
 pub mod json;
 pub mod logging;
+pub mod magic_rollback;
 pub mod nixos;
 pub mod search;
+pub mod ssh;
 pub mod update;
 pub mod util;
+pub mod which;
 
 pub use color_eyre::Result;
 
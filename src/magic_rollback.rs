@@ -0,0 +1,428 @@
+//! Rolling a remote host back when a deployment cuts it off.
+//!
+//! Before activating on a target host, nh arms a transient systemd timer
+//! there that re-activates the previous generation. Activation can take a
+//! while, so the timer only gets its real timeout once activation is over.
+//! Then nh opens a new ssh connection to the host and leaves a confirmation
+//! behind, which the timer finds and does nothing. If the new configuration
+//! broke the network or ssh, no confirmation arrives and the host rolls back
+//! on its own.
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+  path::{Path, PathBuf},
+  process::{self, Stdio},
+  thread,
+  time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{Context, bail, eyre};
+use tracing::{debug, info};
+
+use crate::{
+  Result,
+  commands::{Command, ElevationStrategy, shell_quote},
+  interrupt,
+  ssh::SshTarget,
+};
+
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// How long one attempt to reconnect may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time kept between the last attempt to reconnect and the rollback, so that
+/// a confirmation cannot race the timer.
+const MARGIN: Duration = Duration::from_secs(5);
+
+/// How long the timer waits while activation runs. It only fires if nh loses
+/// the host in the middle of activating.
+const GUARD: Duration = Duration::from_secs(30 * 60);
+
+/// A rollback armed on a target host.
+#[derive(Debug)]
+pub struct MagicRollback {
+  target:    SshTarget,
+  token:     String,
+  unit:      String,
+  script:    String,
+  previous:  PathBuf,
+  timeout:   Duration,
+  armed_for: Duration,
+  armed_at:  Instant,
+  elevation: Option<ElevationStrategy>,
+  dry:       bool,
+}
+
+impl MagicRollback {
+  /// Arms a timer on `target` that re-activates the generation it is running
+  /// now, unless [`MagicRollback::confirm`] reaches it first. Until
+  /// [`MagicRollback::start`] is called after activation, the timer waits
+  /// long enough for any activation to finish. With `boot`, the system
+  /// profile and the bootloader are rolled back too.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the current generation cannot be found or the timer
+  /// cannot be started.
+  pub fn arm(
+    target: &SshTarget,
+    timeout: Duration,
+    boot: bool,
+    elevation: Option<ElevationStrategy>,
+    dry: bool,
+  ) -> Result<Self> {
+    if timeout <= MARGIN + CONNECT_TIMEOUT {
+      bail!(
+        "The rollback timeout must be longer than {}",
+        humantime::format_duration(MARGIN + CONNECT_TIMEOUT)
+      );
+    }
+
+    let current = Command::new("readlink")
+      .args(["-f", CURRENT_SYSTEM, SYSTEM_PROFILE])
+      .ssh(Some(target.clone()))
+      .dry(dry)
+      .run_capture()
+      .wrap_err_with(|| {
+        format!("Failed to find the current generation of {target}")
+      })?;
+    let (running, profile) = match current.as_deref().map(str::lines) {
+      Some(mut lines) => {
+        match (lines.next(), lines.next()) {
+          (Some(running), Some(profile)) => {
+            (PathBuf::from(running), PathBuf::from(profile))
+          },
+          _ => {
+            bail!("Failed to find the current generation of {target}");
+          },
+        }
+      },
+      // Dry run
+      None => (PathBuf::from(CURRENT_SYSTEM), PathBuf::from(SYSTEM_PROFILE)),
+    };
+
+    let token = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let script = rollback_script(&token, &running, boot.then_some(&*profile));
+
+    let unit = format!("{}-guard", unit(&token));
+
+    Command::new("systemd-run")
+      .args(timer_command(&unit, GUARD, &script))
+      .ssh(Some(target.clone()))
+      .elevate(elevation.clone())
+      .message(format!(
+        "Arming a rollback of {target} to {}",
+        running.display()
+      ))
+      .dry(dry)
+      .with_required_env()
+      .run()
+      .wrap_err_with(|| format!("Failed to arm the rollback on {target}"))?;
+
+    Ok(Self {
+      target: target.clone(),
+      token,
+      unit,
+      script,
+      previous: running,
+      timeout,
+      armed_for: GUARD,
+      armed_at: Instant::now(),
+      elevation,
+      dry,
+    })
+  }
+
+  /// Starts the countdown to the rollback, once activation is over. The new
+  /// timer is started before the one armed for activation is stopped, so the
+  /// host is never left without one.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the timer cannot be replaced, in which case the one
+  /// armed for activation may still roll the host back.
+  pub fn start(&mut self) -> Result<()> {
+    let unit = unit(&self.token);
+    let start = ["systemd-run".to_string()]
+      .into_iter()
+      .chain(timer_command(&unit, self.timeout, &self.script))
+      .map(|arg| shell_quote(&arg).into_owned())
+      .collect::<Vec<_>>()
+      .join(" ");
+    let stop = format!(
+      "systemctl stop {}",
+      shell_quote(&format!("{}.timer", self.unit))
+    );
+
+    Command::new("/bin/sh")
+      .args(["-c", &format!("{start} && {stop}")])
+      .ssh(Some(self.target.clone()))
+      .elevate(self.elevation.clone())
+      .message(format!(
+        "Rolling {} back to {} in {} unless nh can reconnect",
+        self.target,
+        self.previous.display(),
+        humantime::format_duration(self.timeout)
+      ))
+      .dry(self.dry)
+      .with_required_env()
+      .run()
+      .wrap_err_with(|| {
+        format!("Failed to start the rollback timer on {}", self.target)
+      })
+      .wrap_err_with(|| self.rolling_back())?;
+
+    self.unit = unit;
+    self.armed_for = self.timeout;
+    self.armed_at = Instant::now();
+    Ok(())
+  }
+
+  /// Reconnects to the target with a new ssh connection until it works or
+  /// the timer is about to fire, and confirms the deployment.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the target could not be reached in time, in which
+  /// case it rolls back on its own.
+  pub fn confirm(&self) -> Result<()> {
+    let confirm = format!("touch {}", confirmation(&self.token));
+    if self.dry {
+      println!("ssh {}", confirm_command(&self.target, &confirm).join(" "));
+      return Ok(());
+    }
+
+    // Every attempt must be over before the timer fires, or the host could
+    // be confirmed after it already rolled back
+    let deadline = self.armed_for - MARGIN;
+    let mut attempt = 0;
+    loop {
+      if self.armed_at.elapsed() + CONNECT_TIMEOUT >= deadline {
+        return Err(eyre!(
+          "Failed to reconnect to {} after activation",
+          self.target
+        ))
+        .wrap_err_with(|| self.rolling_back());
+      }
+      attempt += 1;
+      interrupt::check().wrap_err_with(|| self.rolling_back())?;
+
+      let status = process::Command::new("ssh")
+        .args(confirm_command(&self.target, &confirm))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+      match status {
+        Ok(status) if status.success() => {
+          info!("Reconnected to {}, keeping the new generation", self.target);
+          return Ok(());
+        },
+        other => debug!(?other, attempt, "Failed to reconnect"),
+      }
+      thread::sleep(Duration::from_secs(2));
+    }
+  }
+
+  /// Rolls back right away, after activation failed.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the target cannot be reached, in which case the
+  /// timer still rolls it back later.
+  pub fn roll_back_now(&self) -> Result<()> {
+    Command::new("systemctl")
+      .args(["start", &format!("{}.service", self.unit)])
+      .ssh(Some(self.target.clone()))
+      .elevate(self.elevation.clone())
+      .message(format!(
+        "Rolling {} back to {}",
+        self.target,
+        self.previous.display()
+      ))
+      .dry(self.dry)
+      .with_required_env()
+      .run()
+      .wrap_err_with(|| self.rolling_back())
+  }
+
+  fn rolling_back(&self) -> String {
+    let left = self.armed_for.saturating_sub(self.armed_at.elapsed());
+    format!(
+      "{} rolls back to {} in {}",
+      self.target,
+      self.previous.display(),
+      humantime::format_duration(Duration::from_secs(left.as_secs()))
+    )
+  }
+}
+
+fn unit(token: &str) -> String {
+  format!("nh-rollback-{token}")
+}
+
+/// Returns the arguments to systemd-run that run `script` as `unit` after
+/// `timeout`.
+fn timer_command(unit: &str, timeout: Duration, script: &str) -> Vec<String> {
+  [
+    format!("--unit={unit}"),
+    "--description=nh magic rollback".to_string(),
+    format!("--on-active={}", timeout.as_secs()),
+    "--timer-property=AccuracySec=1s".to_string(),
+    "--collect".to_string(),
+    "/bin/sh".to_string(),
+    "-c".to_string(),
+    script.to_string(),
+  ]
+  .into()
+}
+
+fn confirmation(token: &str) -> String {
+  format!("/tmp/nh-rollback-{token}.confirmed")
+}
+
+/// Returns the arguments to ssh that run `command` on `target` over a new
+/// connection, as the shared one may survive a change that blocks new ones.
+fn confirm_command(target: &SshTarget, command: &str) -> Vec<String> {
+  // ssh keeps the first value of an option, so these must come first
+  let mut args: Vec<String> = [
+    "-o",
+    "ControlMaster=no",
+    "-o",
+    "ControlPath=none",
+    "-o",
+    &format!("ConnectTimeout={}", CONNECT_TIMEOUT.as_secs()),
+  ]
+  .map(str::to_string)
+  .into();
+  args.extend(target.ssh_args());
+  args.push(command.to_string());
+  args
+}
+
+/// Returns the script the timer runs: nothing if the deployment was
+/// confirmed, and otherwise switch back to `running`, after setting the
+/// system profile back to `profile` and installing its boot entry.
+fn rollback_script(
+  token: &str,
+  running: &Path,
+  profile: Option<&Path>,
+) -> String {
+  let quote = |path: &Path| shell_quote(&path.to_string_lossy()).into_owned();
+  let confirmation = confirmation(token);
+  let running = quote(running);
+  let boot = profile.map_or_else(String::new, |profile| {
+    let profile = quote(profile);
+    format!(
+      "{profile}/sw/bin/nix-env -p {SYSTEM_PROFILE} --set {profile}; \
+       {profile}/bin/switch-to-configuration boot; "
+    )
+  });
+  format!(
+    "if [ -e {confirmation} ]; then rm -f {confirmation}; else \
+     {boot}{running}/bin/switch-to-configuration test; fi"
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, os::unix::fs::PermissionsExt};
+
+  use super::*;
+
+  /// Creates a generation whose scripts log how they were called.
+  fn generation(dir: &Path, name: &str) -> PathBuf {
+    let generation = dir.join(name);
+    let log = dir.join("log");
+    for script in ["bin/switch-to-configuration", "sw/bin/nix-env"] {
+      let path = generation.join(script);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(
+        &path,
+        format!(
+          "#!/bin/sh\necho {name} {script} \"$@\" >> {}\n",
+          log.display()
+        ),
+      )
+      .unwrap();
+      fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    generation
+  }
+
+  fn run(script: &str) {
+    let status = process::Command::new("sh")
+      .args(["-c", script])
+      .status()
+      .unwrap();
+    assert!(status.success());
+  }
+
+  #[test]
+  fn test_rollback_script() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("log");
+    let running = generation(dir.path(), "running");
+    let profile = generation(dir.path(), "profile");
+    let token = format!("test-{}", process::id());
+
+    // Confirmed, nothing is rolled back and the confirmation is cleaned up
+    fs::write(confirmation(&token), "").unwrap();
+    run(&rollback_script(&token, &running, Some(&profile)));
+    assert!(!log.exists());
+    assert!(!Path::new(&confirmation(&token)).exists());
+
+    run(&rollback_script(&token, &running, Some(&profile)));
+    let calls = fs::read_to_string(&log).unwrap();
+    let calls: Vec<&str> = calls.lines().collect();
+    assert_eq!(calls, [
+      format!(
+        "profile sw/bin/nix-env -p {SYSTEM_PROFILE} --set {}",
+        profile.display()
+      ),
+      "profile bin/switch-to-configuration boot".to_string(),
+      "running bin/switch-to-configuration test".to_string(),
+    ]);
+
+    fs::remove_file(&log).unwrap();
+    run(&rollback_script(&token, &running, None));
+    assert_eq!(
+      fs::read_to_string(&log).unwrap(),
+      "running bin/switch-to-configuration test\n"
+    );
+  }
+
+  #[test]
+  fn test_confirm_fails_after_the_deadline() {
+    let timeout = Duration::from_secs(30);
+    let rollback = MagicRollback {
+      target: "root@host".parse().unwrap(),
+      token: "test".to_string(),
+      unit: unit("test"),
+      script: String::new(),
+      previous: PathBuf::from("/nix/store/previous"),
+      timeout,
+      armed_for: timeout,
+      // Too late for even one attempt, which would otherwise run ssh
+      armed_at: Instant::now() - (timeout - MARGIN - CONNECT_TIMEOUT),
+      elevation: None,
+      dry: false,
+    };
+    assert!(rollback.confirm().is_err());
+  }
+
+  #[test]
+  fn test_confirm_uses_a_new_connection() {
+    let target: SshTarget = "root@host:2222".parse().unwrap();
+    let args = confirm_command(&target, "touch /tmp/x");
+    assert_eq!(&args[..4], [
+      "-o",
+      "ControlMaster=no",
+      "-o",
+      "ControlPath=none"
+    ]);
+    assert_eq!(&args[args.len() - 2..], ["root@host", "touch /tmp/x"]);
+  }
+}
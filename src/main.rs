@@ -3,16 +3,23 @@ mod clean;
 mod commands;
 mod completion;
 mod darwin;
+mod deploy;
+mod flake;
 mod generations;
+mod git;
 mod home;
 mod installable;
 mod interface;
+mod interrupt;
 mod json;
 mod logging;
+mod magic_rollback;
 mod nixos;
 mod search;
+mod ssh;
 mod update;
 mod util;
+mod which;
 
 use color_eyre::Result;
 
@@ -29,6 +36,10 @@ fn main() -> Result<()> {
   tracing::debug!("{args:#?}");
   tracing::debug!(%NH_VERSION, ?NH_REV);
 
+  // Exit through the normal error path on Ctrl-C, so that temporary files are
+  // cleaned up
+  interrupt::install()?;
+
   // Check Nix version upfront
   checks::verify_nix_environment()?;
 
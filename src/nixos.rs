@@ -1,7 +1,9 @@
 use std::{
+  collections::BTreeMap,
   env,
   fs,
   path::{Path, PathBuf},
+  time::{Duration, Instant},
 };
 
 use color_eyre::eyre::{Context, Result, bail, eyre};
@@ -9,8 +11,11 @@ use tracing::{debug, info, warn};
 
 use crate::{
   commands,
-  commands::{Command, ElevationStrategy},
+  commands::{Command, ElevationStrategy, SudoKeepAlive, shell_quote},
+  deploy::{self, DeployTarget, Outcome},
+  flake,
   generations,
+  git,
   installable::Installable,
   interface::{
     self,
@@ -22,8 +27,16 @@ use crate::{
     OsRollbackArgs,
     OsSubcommand::{self},
   },
-  update::update,
-  util::{ensure_ssh_key_login, get_hostname, print_dix_diff},
+  interrupt,
+  magic_rollback::MagicRollback,
+  ssh::{SshSession, SshTarget},
+  update::{LockSnapshot, update},
+  util::{
+    copy_from_remote,
+    ensure_ssh_key_login,
+    get_hostname,
+    print_dix_diff,
+  },
 };
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
@@ -82,11 +95,50 @@ impl OsRebuildArgs {
   ) -> Result<()> {
     use OsRebuildVariant::{Boot, Build, BuildVm, Switch, Test};
 
-    if self.build_host.is_some() || self.target_host.is_some() {
+    let targets = match &self.inventory {
+      Some(path) => deploy::read_inventory(path)?,
+      None => self.target_host.clone(),
+    };
+    let many_targets = targets.len() > 1 || self.inventory.is_some();
+    if many_targets && matches!(variant, BuildVm) {
+      bail!("A VM cannot be deployed to several hosts");
+    }
+
+    // A single target gets the configuration of this host, unless it is named
+    let (target_host, target_name) = match targets.as_slice() {
+      [target] if !many_targets => {
+        (Some(target.target.clone()), target.name.clone())
+      },
+      _ => (None, None),
+    };
+
+    if self.magic_rollback
+      && (targets.is_empty() || !matches!(variant, Switch | Test))
+    {
+      warn!(
+        "--magic-rollback only has an effect for `nh os switch` and `nh os \
+         test` with --target-host"
+      );
+    }
+
+    if self.build_host.is_some() || !targets.is_empty() {
       // if it fails its okay
       let _ = ensure_ssh_key_login();
     }
 
+    // Every step of the deployment reuses one connection per host
+    let _ssh_session = if self.common.dry {
+      None
+    } else {
+      let hosts = self
+        .build_host
+        .iter()
+        .chain(targets.iter().map(|target| &target.target));
+      (self.build_host.is_some() || !targets.is_empty())
+        .then(|| SshSession::start(hosts))
+        .transpose()?
+    };
+
     let elevate = if self.bypass_root_check {
       warn!("Bypassing root check, now running nix as root");
       false
@@ -97,47 +149,98 @@ impl OsRebuildArgs {
       true
     };
 
-    if self.update_args.update_all || self.update_args.update_input.is_some() {
-      update(&self.common.installable, self.update_args.update_input)?;
-    }
+    // Authenticate before a long build rather than after it, when nobody may
+    // be around to type the password
+    let _sudo_keepalive = if elevate
+      && targets.is_empty()
+      && !self.common.dry
+      && !self.no_sudo_keepalive
+      && !matches!(variant, Build | BuildVm)
+    {
+      SudoKeepAlive::start(&elevation)?
+    } else {
+      None
+    };
 
-    let system_hostname = match get_hostname() {
-      Ok(hostname) => Some(hostname),
-      Err(err) => {
-        tracing::warn!("{}", err.to_string());
-        None
-      },
+    // Use NH_OS_FLAKE if available, otherwise use the provided installable
+    let installable = if let Ok(os_flake) = env::var("NH_OS_FLAKE") {
+      debug!("Using NH_OS_FLAKE: {}", os_flake);
+
+      Installable::from_flake_str(&os_flake)
+        .wrap_err("Invalid flake reference in NH_OS_FLAKE")?
+    } else {
+      self.common.installable.clone()
     };
 
-    let target_hostname = match &self.hostname {
-      Some(h) => h.to_owned(),
-      None => {
-        match &system_hostname {
-          Some(hostname) => {
-            // Only show the warning if we're explicitly building a VM
-            // by directly calling build_vm(), not when the BuildVm variant
-            // is used internally via other code paths
-            if matches!(variant, OsRebuildVariant::BuildVm)
-              && final_attr
-                .as_deref()
-                .is_some_and(|attr| attr == "vm" || attr == "vmWithBootLoader")
-            {
-              tracing::warn!(
-                "Guessing system is {hostname} for a VM image. If this isn't \
-                 intended, use --hostname to change."
-              );
-            }
-            hostname.clone()
-          },
-          None => {
-            return Err(eyre!(
-              "Unable to fetch hostname, and no hostname supplied."
-            ));
-          },
-        }
-      },
+    let system_hostname = if many_targets {
+      None
+    } else {
+      match get_hostname() {
+        Ok(hostname) => Some(hostname),
+        Err(err) => {
+          tracing::warn!("{}", err.to_string());
+          None
+        },
+      }
+    };
+
+    let configurations = if many_targets {
+      targets
+        .iter()
+        .map(|target| {
+          match (&target.name, &self.hostname) {
+            (Some(name), _) | (None, Some(name)) => Ok(name.clone()),
+            (None, None) => target.configuration(),
+          }
+        })
+        .collect::<Result<Vec<_>>>()?
+    } else {
+      vec![self.target_hostname(
+        variant,
+        final_attr.as_deref(),
+        target_name.as_ref(),
+        system_hostname.as_deref(),
+      )?]
     };
 
+    // Checked before --update rewrites the lock file, which would make the
+    // tree dirty
+    if matches!(variant, Switch | Boot)
+      && configurations.iter().any(|name| self.requires_clean(name))
+    {
+      git::ensure_clean(&installable, self.allow_dirty)?;
+    }
+
+    let lock_snapshot = update(
+      &self.common.installable,
+      self.update_args.clone(),
+      elevate.then_some(elevation.clone()),
+    )?;
+
+    flake::warn_stale_inputs(&self.common.installable);
+
+    git::check_untracked_files(
+      &installable,
+      self.common.add_untracked,
+      self.common.ask,
+      self.common.dry,
+    )?;
+
+    if many_targets {
+      return self.deploy_many(
+        variant,
+        &installable,
+        &targets,
+        &configurations,
+        elevate,
+        &elevation,
+        lock_snapshot,
+      );
+    }
+
+    // There is a single configuration without several targets
+    let target_hostname = configurations[0].clone();
+
     let (out_path, _tempdir_guard): (PathBuf, Option<tempfile::TempDir>) =
       match self.common.out_link {
         Some(ref p) => (p.clone(), None),
@@ -154,49 +257,20 @@ impl OsRebuildArgs {
 
     debug!("Output path: {out_path:?}");
 
-    // Use NH_OS_FLAKE if available, otherwise use the provided installable
-    let installable = if let Ok(os_flake) = env::var("NH_OS_FLAKE") {
-      debug!("Using NH_OS_FLAKE: {}", os_flake);
-
-      let mut elems = os_flake.splitn(2, '#');
-      let reference = elems
-        .next()
-        .ok_or_else(|| eyre!("NH_OS_FLAKE missing reference part"))?
-        .to_owned();
-      let attribute = elems
-        .next()
-        .map(crate::installable::parse_attribute)
-        .unwrap_or_default();
-
-      Installable::Flake {
-        reference,
-        attribute,
-      }
-    } else {
-      self.common.installable.clone()
-    };
-
-    let toplevel = toplevel_for(
-      &target_hostname,
-      installable,
-      final_attr.unwrap_or(String::from("toplevel")).as_str(),
-    );
-
     let message = match variant {
       BuildVm => "Building NixOS VM image",
       _ => "Building NixOS configuration",
     };
 
-    commands::Build::new(toplevel)
-      .extra_arg("--out-link")
-      .extra_arg(&out_path)
-      .extra_args(&self.extra_args)
-      .passthrough(&self.common.passthrough)
-      .builder(self.build_host.clone())
-      .message(message)
-      .nom(!self.common.no_nom)
-      .run()
-      .wrap_err("Failed to build configuration")?;
+    let source = self.copy_source(variant, &targets);
+    let out_path = self.build_configuration(
+      &target_hostname,
+      installable,
+      final_attr.as_deref().unwrap_or("toplevel"),
+      message,
+      &out_path,
+      source.is_some(),
+    )?;
 
     let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
 
@@ -217,9 +291,11 @@ impl OsRebuildArgs {
     debug!("Target profile path: {}", target_profile.display());
     debug!("Target profile exists: {}", target_profile.exists());
 
-    if !target_profile
-      .try_exists()
-      .context("Failed to check if target profile exists")?
+    // A configuration left on the build host is not here to check
+    if source.is_none()
+      && !target_profile
+        .try_exists()
+        .context("Failed to check if target profile exists")?
     {
       return Err(eyre!(
         "Target profile path does not exist: {}",
@@ -227,17 +303,33 @@ impl OsRebuildArgs {
       ));
     }
 
-    match self.common.diff {
-      DiffType::Always => {
+    match (&self.common.diff, &target_host) {
+      (DiffType::Never, _) => {
+        debug!("Not running dix as the --diff flag is set to never.");
+      },
+      (_, Some(_)) if source.is_some() => {
+        debug!(
+          "Not running dix as the configuration is not copied here from the \
+           build host."
+        );
+      },
+      // The target host is what the configuration will replace
+      (_, Some(target)) => {
+        // The diff is informational, activation reports an unreachable host
+        match remote_current_system(target, self.common.dry) {
+          Ok(Some(current)) => {
+            let _ = print_dix_diff(&current, &target_profile);
+          },
+          Ok(None) => {},
+          Err(err) => warn!("Not showing the diff: {err:?}"),
+        }
+      },
+      (DiffType::Always, None) => {
         let _ =
           print_dix_diff(&PathBuf::from(CURRENT_PROFILE), &target_profile);
       },
-      DiffType::Never => {
-        debug!("Not running dix as the --diff flag is set to never.");
-      },
-      DiffType::Auto => {
+      (DiffType::Auto, None) => {
         if system_hostname.is_none_or(|h| h == target_hostname)
-          && self.target_host.is_none()
           && self.build_host.is_none()
         {
           debug!(
@@ -255,14 +347,19 @@ impl OsRebuildArgs {
       },
     }
 
-    if self.common.dry || matches!(variant, Build | BuildVm) {
-      if self.common.ask {
-        warn!("--ask has no effect as dry run was requested");
+    if matches!(variant, Build | BuildVm) {
+      if let Some(snapshot) = lock_snapshot {
+        snapshot.keep();
       }
       return Ok(());
     }
 
-    if self.common.ask {
+    if self.common.dry {
+      if self.common.ask {
+        warn!("--ask has no effect as dry run was requested");
+      }
+      info!("Dry run, printing the commands that would be run");
+    } else if self.common.ask {
       let confirmation = inquire::Confirm::new("Apply the config?")
         .with_default(false)
         .prompt()?;
@@ -272,29 +369,190 @@ impl OsRebuildArgs {
       }
     }
 
-    if let Some(target_host) = &self.target_host {
-      Command::new("nix")
-        .args([
-          "copy",
-          "--to",
-          format!("ssh://{target_host}").as_str(),
-          match target_profile.to_str() {
-            Some(s) => s,
-            None => {
-              return Err(eyre!("target_profile path is not valid UTF-8"));
-            },
+    self.activate(
+      variant,
+      &out_path,
+      &target_profile,
+      target_host.as_ref(),
+      source,
+      elevate,
+      &elevation,
+    )?;
+
+    debug!("Completed operation with output path: {out_path:?}");
+
+    if let Some(snapshot) = lock_snapshot {
+      snapshot.keep();
+    }
+
+    Ok(())
+  }
+
+  /// Builds the configuration of `hostname` into `out_path`, and returns the
+  /// path of the result. With `keep_remote`, the result stays on the build
+  /// host and the path is the store path it has there.
+  fn build_configuration(
+    &self,
+    hostname: &str,
+    installable: Installable,
+    final_attr: &str,
+    message: &str,
+    out_path: &Path,
+    keep_remote: bool,
+  ) -> Result<PathBuf> {
+    let toplevel = toplevel_for(hostname, installable, final_attr);
+
+    commands::Build::new(toplevel)
+      .out_link(out_path)
+      .extra_args(&self.extra_args)
+      .passthrough(&self.common.passthrough)
+      .builder(self.build_host.clone())
+      .copy_options(&self.copy)
+      .keep_remote(keep_remote)
+      .message(message)
+      .nom(!self.common.no_nom)
+      .run()
+      .wrap_err("Failed to build configuration")?;
+
+    if keep_remote {
+      std::fs::read_link(out_path)
+        .wrap_err_with(|| format!("Failed to resolve {}", out_path.display()))
+    } else {
+      Ok(out_path.to_path_buf())
+    }
+  }
+
+  /// Returns the build host when the configuration is copied from there
+  /// straight to the targets instead of through this host. `nix copy` gives
+  /// both hosts the same ssh options, so they must not differ.
+  fn copy_source(
+    &self,
+    variant: &OsRebuildVariant,
+    targets: &[DeployTarget],
+  ) -> Option<&SshTarget> {
+    let builder = self.build_host.as_ref()?;
+    if targets.is_empty()
+      || matches!(variant, OsRebuildVariant::Build | OsRebuildVariant::BuildVm)
+    {
+      return None;
+    }
+    if let Some(target) = targets
+      .iter()
+      .find(|target| target.target.nix_sshopts() != builder.nix_sshopts())
+    {
+      debug!(
+        "Copying the configuration through this host, as {builder} and {} use \
+         different ssh options",
+        target.target
+      );
+      return None;
+    }
+    Some(builder)
+  }
+
+  /// Returns the configuration to build for a single target: the one given
+  /// with --hostname or the target, or else that of this host.
+  fn target_hostname(
+    &self,
+    variant: &OsRebuildVariant,
+    final_attr: Option<&str>,
+    target_name: Option<&String>,
+    system_hostname: Option<&str>,
+  ) -> Result<String> {
+    match self.hostname.as_ref().or(target_name) {
+      Some(h) => Ok(h.to_owned()),
+      None => {
+        match system_hostname {
+          Some(hostname) => {
+            // Only show the warning if we're explicitly building a VM
+            // by directly calling build_vm(), not when the BuildVm variant
+            // is used internally via other code paths
+            if matches!(variant, OsRebuildVariant::BuildVm)
+              && final_attr
+                .is_some_and(|attr| attr == "vm" || attr == "vmWithBootLoader")
+            {
+              tracing::warn!(
+                "Guessing system is {hostname} for a VM image. If this isn't \
+                 intended, use --hostname to change."
+              );
+            }
+            Ok(hostname.to_owned())
           },
-        ])
-        .message("Copying configuration to target")
-        .with_required_env()
+          None => {
+            Err(eyre!("Unable to fetch hostname, and no hostname supplied."))
+          },
+        }
+      },
+    }
+  }
+
+  /// Copies the built configuration to `target_host`, if any, and activates
+  /// it there as `variant` requires. The configuration is copied from
+  /// `source` when it was left on the build host.
+  #[expect(clippy::too_many_lines, clippy::too_many_arguments)]
+  fn activate(
+    &self,
+    variant: &OsRebuildVariant,
+    out_path: &Path,
+    target_profile: &Path,
+    target_host: Option<&SshTarget>,
+    source: Option<&SshTarget>,
+    elevate: bool,
+    elevation: &ElevationStrategy,
+  ) -> Result<()> {
+    use OsRebuildVariant::{Boot, Switch, Test};
+
+    let on = target_host
+      .map(|host| format!(" on {host}"))
+      .unwrap_or_default();
+
+    if let Some(target_host) = target_host {
+      let (copy, from) = match source {
+        Some(source) => {
+          (
+            commands::Copy::between(source, target_host),
+            format!(" from {source}"),
+          )
+        },
+        None => (commands::Copy::to(target_host), String::new()),
+      };
+      copy
+        .path(target_profile)
+        .options(&self.copy)
+        .message(format!("Copying configuration{from} to {target_host}"))
+        .dry(self.common.dry)
         .run()?;
     }
 
-    if let Test | Switch = variant {
-      let switch_to_configuration =
-        target_profile.join("bin").join("switch-to-configuration");
+    // Arm the rollback before anything can cut the host off, its countdown
+    // starts once activation is over
+    let rollback = match (self.magic_rollback, target_host, variant) {
+      (true, Some(host), Test | Switch) => {
+        Some(MagicRollback::arm(
+          host,
+          self.rollback_timeout.into(),
+          matches!(variant, Switch),
+          elevate.then_some(elevation.clone()),
+          self.common.dry,
+        )?)
+      },
+      _ => None,
+    };
 
-      if !switch_to_configuration.exists() {
+    // A configuration left on the build host is not here to check or
+    // resolve, its store paths are used as they are on the target
+    let resolve = |path: &Path| -> Result<PathBuf> {
+      if source.is_some() {
+        Ok(path.to_path_buf())
+      } else {
+        path
+          .canonicalize()
+          .wrap_err_with(|| format!("Failed to resolve {}", path.display()))
+      }
+    };
+    let switch_to_configuration = |profile: &Path| -> Result<PathBuf> {
+      let path = profile.join("bin").join("switch-to-configuration");
+      if source.is_none() && !path.exists() {
         return Err(eyre!(
           "The 'switch-to-configuration' binary is missing from the built \
            configuration.\n\nThis typically happens when \
@@ -305,80 +563,328 @@ impl OsRebuildArgs {
            persists, please open an issue on our issue tracker!"
         ));
       }
+      resolve(&path)
+    };
+
+    let activation = || -> Result<()> {
+      if let Test | Switch = variant {
+        let switch_to_configuration = switch_to_configuration(target_profile)?;
+        let switch_to_configuration =
+          switch_to_configuration.to_str().ok_or_else(|| {
+            eyre!("switch-to-configuration path contains invalid UTF-8")
+          })?;
+
+        Command::new(switch_to_configuration)
+          .arg("test")
+          .ssh(target_host.cloned())
+          .message(format!("Activating configuration{on}"))
+          .dry(self.common.dry)
+          .elevate(elevate.then_some(elevation.clone()))
+          .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
+          .with_required_env()
+          .run()
+          .wrap_err("Activation (test) failed")?;
+      }
+
+      if let Boot | Switch = variant {
+        let canonical_out_path = resolve(out_path)?;
+        let switch_to_configuration = switch_to_configuration(out_path)?;
+        let switch_to_configuration =
+          switch_to_configuration.to_str().ok_or_else(|| {
+            eyre!("switch-to-configuration path contains invalid UTF-8")
+          })?;
+
+        // Setting the profile without updating the bootloader would boot into
+        // an older generation than the profile claims, so signals are deferred
+        // until both are done
+        interrupt::critical(|| {
+          Command::new("nix")
+            .elevate(elevate.then_some(elevation.clone()))
+            .args(["build", "--no-link", "--profile", SYSTEM_PROFILE])
+            .dry(self.common.dry)
+            .arg(&canonical_out_path)
+            .ssh(target_host.cloned())
+            .with_required_env()
+            .run()
+            .wrap_err("Failed to set system profile")?;
 
-      let switch_to_configuration = switch_to_configuration
-        .canonicalize()
-        .context("Failed to resolve switch-to-configuration path")?;
-      let switch_to_configuration =
-        switch_to_configuration.to_str().ok_or_else(|| {
-          eyre!("switch-to-configuration path contains invalid UTF-8")
+          Command::new(switch_to_configuration)
+            .arg("boot")
+            .ssh(target_host.cloned())
+            .elevate(elevate.then_some(elevation.clone()))
+            .message(format!("Adding configuration to bootloader{on}"))
+            .dry(self.common.dry)
+            .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
+            .with_required_env()
+            .run()
+            .wrap_err_with(|| {
+              format!(
+                "Bootloader activation failed, {SYSTEM_PROFILE} already \
+                 points to {}",
+                canonical_out_path.display()
+              )
+            })
+        })?;
+
+        interrupt::check().wrap_err_with(|| {
+          format!(
+            "Exiting after the interrupted switch completed: {SYSTEM_PROFILE} \
+             points to {} and it is the default boot entry",
+            canonical_out_path.display()
+          )
         })?;
+      }
+      Ok(())
+    };
 
-      Command::new(switch_to_configuration)
-        .arg("test")
-        .ssh(self.target_host.clone())
-        .message("Activating configuration")
-        .elevate(elevate.then_some(elevation.clone()))
-        .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
-        .with_required_env()
-        .run()
-        .wrap_err("Activation (test) failed")?;
+    match (activation(), rollback) {
+      (Ok(()), Some(mut rollback)) => {
+        rollback.start()?;
+        rollback.confirm()
+      },
+      (Err(err), Some(rollback)) => {
+        if let Err(rollback_err) = rollback.roll_back_now() {
+          warn!("{rollback_err:?}");
+        }
+        Err(err)
+      },
+      (result, None) => result,
     }
+  }
 
-    if let Boot | Switch = variant {
-      let canonical_out_path = out_path
-        .canonicalize()
-        .context("Failed to resolve output path")?;
+  /// Returns the configuration to activate on other hosts, which get the
+  /// specialisation given on the command line rather than the local one.
+  fn target_profile(&self, out_path: &Path) -> PathBuf {
+    match (&self.specialisation, self.no_specialisation) {
+      (Some(spec), false) => out_path.join("specialisation").join(spec),
+      _ => out_path.to_path_buf(),
+    }
+  }
 
-      Command::new("nix")
-        .elevate(elevate.then_some(elevation.clone()))
-        .args(["build", "--no-link", "--profile", SYSTEM_PROFILE])
-        .arg(&canonical_out_path)
-        .ssh(self.target_host.clone())
-        .with_required_env()
-        .run()
-        .wrap_err("Failed to set system profile")?;
+  /// Builds the configuration of every target, then copies and activates
+  /// them on `--parallel` hosts at a time, and prints how it went for each.
+  /// `configurations` holds the name of the configuration of each target.
+  #[expect(clippy::too_many_arguments)]
+  fn deploy_many(
+    &self,
+    variant: &OsRebuildVariant,
+    installable: &Installable,
+    targets: &[DeployTarget],
+    configurations: &[String],
+    elevate: bool,
+    elevation: &ElevationStrategy,
+    lock_snapshot: Option<LockSnapshot>,
+  ) -> Result<()> {
+    use OsRebuildVariant::Build;
 
-      let switch_to_configuration =
-        out_path.join("bin").join("switch-to-configuration");
+    let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
+    let source = self.copy_source(variant, targets);
 
-      if !switch_to_configuration.exists() {
-        return Err(eyre!(
-          "The 'switch-to-configuration' binary is missing from the built \
-           configuration.\n\nThis typically happens when \
-           'system.switch.enable' is set to false in your\nNixOS \
-           configuration. To fix this, please either:\n1. Remove \
-           'system.switch.enable = false' from your configuration, or\n2. Set \
-           'system.switch.enable = true' explicitly\n\nIf the problem \
-           persists, please open an issue on our issue tracker!"
-        ));
+    // Hosts sharing a configuration share its build as well
+    let mut builds: BTreeMap<&str, Result<(PathBuf, Duration), String>> =
+      BTreeMap::new();
+    for name in configurations {
+      if builds.contains_key(name.as_str()) {
+        continue;
+      }
+      let out_path = match &self.common.out_link {
+        Some(out_link) => {
+          let mut out_link = out_link.clone().into_os_string();
+          out_link.push(format!("-{name}"));
+          PathBuf::from(out_link)
+        },
+        None => dir.path().join(name),
+      };
+      let start = Instant::now();
+      let built = self.build_configuration(
+        name,
+        installable.clone(),
+        "toplevel",
+        &format!("Building NixOS configuration {name}"),
+        &out_path,
+        source.is_some(),
+      );
+      interrupt::check()?;
+      if let Err(err) = &built {
+        warn!("Failed to build {name}: {err:?}");
       }
+      builds.insert(
+        name,
+        built
+          .map(|out_path| (out_path, start.elapsed()))
+          .map_err(|err| err.to_string()),
+      );
+    }
 
-      let switch_to_configuration = switch_to_configuration
-        .canonicalize()
-        .context("Failed to resolve switch-to-configuration path")?;
-      let switch_to_configuration =
-        switch_to_configuration.to_str().ok_or_else(|| {
-          eyre!("switch-to-configuration path contains invalid UTF-8")
-        })?;
+    let mut outcomes: Vec<Outcome> = targets
+      .iter()
+      .zip(configurations)
+      .map(|(target, name)| {
+        Outcome {
+          configuration: name.clone(),
+          target:        target.clone(),
+          result:        match &builds[name.as_str()] {
+            Ok((_, elapsed)) => Ok(*elapsed),
+            Err(err) => Err(eyre!("build failed: {err}")),
+          },
+        }
+      })
+      .collect();
 
-      Command::new(switch_to_configuration)
-        .arg("boot")
-        .ssh(self.target_host)
-        .elevate(elevate.then_some(elevation))
-        .message("Adding configuration to bootloader")
-        .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
-        .with_required_env()
-        .run()
-        .wrap_err("Bootloader activation failed")?;
+    if source.is_some() {
+      debug!(
+        "Not running dix as the configurations are not copied here from the \
+         build host."
+      );
+    } else if !matches!(self.common.diff, DiffType::Never) {
+      for outcome in &mut outcomes {
+        let Ok((out_path, _)) = &builds[outcome.configuration.as_str()] else {
+          continue;
+        };
+        let target = &outcome.target.target;
+        // A host that cannot be reached fails on its own, the others are
+        // still deployed
+        match remote_current_system(target, self.common.dry) {
+          Ok(Some(current)) => {
+            println!("\n{} on {target}:", outcome.configuration);
+            let _ = print_dix_diff(&current, &self.target_profile(out_path));
+          },
+          Ok(None) => {},
+          Err(err) => {
+            warn!("{err:?}");
+            outcome.result = Err(err);
+          },
+        }
+      }
     }
 
-    debug!("Completed operation with output path: {out_path:?}");
+    if !matches!(variant, Build) {
+      if self.common.dry {
+        if self.common.ask {
+          warn!("--ask has no effect as dry run was requested");
+        }
+        info!("Dry run, printing the commands that would be run");
+      } else if self.common.ask {
+        let confirmation = inquire::Confirm::new(&format!(
+          "Apply the config to {} hosts?",
+          outcomes.iter().filter(|o| o.result.is_ok()).count()
+        ))
+        .with_default(false)
+        .prompt()?;
+
+        if !confirmation {
+          bail!("User rejected the new config");
+        }
+      }
+
+      let built: Vec<(usize, &Outcome, &Path)> = outcomes
+        .iter()
+        .enumerate()
+        .filter(|(_, outcome)| outcome.result.is_ok())
+        .filter_map(|(index, outcome)| {
+          match &builds[outcome.configuration.as_str()] {
+            Ok((out_path, _)) => Some((index, outcome, out_path.as_path())),
+            Err(_) => None,
+          }
+        })
+        .collect();
+
+      let results = deploy::run_parallel(
+        &built,
+        self.parallel,
+        |(_, outcome, out_path)| {
+          self
+            .activate(
+              variant,
+              out_path,
+              &self.target_profile(out_path),
+              Some(&outcome.target.target),
+              source,
+              elevate,
+              elevation,
+            )
+            .wrap_err_with(|| format!("Failed to deploy to {}", outcome.target))
+        },
+      );
+
+      let indices: Vec<usize> =
+        built.iter().map(|(index, ..)| *index).collect();
+      for (index, result) in indices.into_iter().zip(results) {
+        if let Err(err) = &result {
+          warn!("{err:?}");
+        }
+        outcomes[index].result = result;
+      }
+    }
+
+    deploy::print_summary(&outcomes);
+
+    let failed: Vec<&DeployTarget> = outcomes
+      .iter()
+      .filter(|outcome| outcome.result.is_err())
+      .map(|outcome| &outcome.target)
+      .collect();
+
+    if failed.len() < outcomes.len() {
+      if let Some(snapshot) = lock_snapshot {
+        snapshot.keep();
+      }
+    }
+
+    if !failed.is_empty() {
+      let retry = deploy::retry_command(env::args_os(), &failed)
+        .iter()
+        .map(|arg| shell_quote(&arg.to_string_lossy()).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ");
+      info!("To deploy to the failed hosts again, run:\n{retry}");
+      bail!(
+        "Failed to deploy to {} of {} hosts",
+        failed.len(),
+        outcomes.len()
+      );
+    }
 
     Ok(())
   }
 }
 
+/// Resolves `/run/current-system` on `target` and copies it here, so that the
+/// new configuration can be diffed against it. Returns `None` if it cannot be
+/// copied, and in dry runs.
+fn remote_current_system(
+  target: &SshTarget,
+  dry: bool,
+) -> Result<Option<PathBuf>> {
+  let failed = || format!("Failed to resolve {CURRENT_PROFILE} on {target}");
+  let Some(current) = Command::new("readlink")
+    .args(["-f", CURRENT_PROFILE])
+    .ssh(Some(target.clone()))
+    .dry(dry)
+    .run_capture()
+    .wrap_err_with(failed)?
+    .map(|s| s.trim().to_string())
+  else {
+    return Ok(None);
+  };
+  // readlink -f always prints a path, nothing means ssh failed
+  if current.is_empty() {
+    bail!(failed());
+  }
+
+  let current = PathBuf::from(current);
+  match copy_from_remote(target, &current) {
+    Ok(()) => Ok(Some(current)),
+    Err(err) => {
+      warn!(
+        "Not showing the diff, as the current system of {target} could not be \
+         copied: {err}"
+      );
+      Ok(None)
+    },
+  }
+}
+
 impl OsRollbackArgs {
   fn rollback(&self, elevation: ElevationStrategy) -> Result<()> {
     let elevate = if self.bypass_root_check {
@@ -713,20 +1219,8 @@ impl OsReplArgs {
     let mut target_installable = if let Ok(os_flake) = env::var("NH_OS_FLAKE") {
       debug!("Using NH_OS_FLAKE: {}", os_flake);
 
-      let mut elems = os_flake.splitn(2, '#');
-      let reference = match elems.next() {
-        Some(r) => r.to_owned(),
-        None => return Err(eyre!("NH_OS_FLAKE missing reference part")),
-      };
-      let attribute = elems
-        .next()
-        .map(crate::installable::parse_attribute)
-        .unwrap_or_default();
-
-      Installable::Flake {
-        reference,
-        attribute,
-      }
+      Installable::from_flake_str(&os_flake)
+        .wrap_err("Invalid flake reference in NH_OS_FLAKE")?
     } else {
       self.installable
     };
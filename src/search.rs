@@ -1,4 +1,8 @@
-use std::{process::Stdio, sync::OnceLock, time::Instant};
+use std::{
+  process::Stdio,
+  sync::OnceLock,
+  time::{Duration, Instant},
+};
 
 use color_eyre::eyre::{Context, bail};
 use elasticsearch_dsl::{
@@ -63,17 +67,7 @@ impl SearchArgs {
   pub fn run(&self) -> Result<()> {
     trace!("args: {self:?}");
 
-    let mut channel = self.channel.clone();
-    if DEPRECATED_VERSIONS.contains(&channel.as_str()) {
-      warn!(
-        "Channel '{channel}' is deprecated or unavailable, falling back to \
-         'nixos-unstable'"
-      );
-      channel = "nixos-unstable".to_string();
-    }
-    if !supported_branch(&channel) {
-      bail!("Channel {channel} is not supported!");
-    }
+    let channel = resolve_channel(&self.channel)?;
 
     let nixpkgs_path = std::thread::spawn(|| {
       std::process::Command::new("nix")
@@ -125,62 +119,8 @@ impl SearchArgs {
         self.channel
       );
     }
-    let then = Instant::now();
 
-    let client = reqwest::blocking::Client::new();
-    let req = client
-            // NOTE: when the version of the backend API changes,
-            // this file and the corresponding workflow called
-            // nixos-search.yaml have to be updated accordingly.
-            .post(format!(
-                "https://search.nixos.org/backend/latest-43-{channel}/_search"
-            ))
-            .json(&query)
-            .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
-            // Hardcoded upstream
-            // https://github.com/NixOS/nixos-search/blob/744ec58e082a3fcdd741b2c9b0654a0f7fda4603/frontend/src/index.js
-            .basic_auth("aWVSALXpZv", Some("X8gPHnzL52wFEekuxsfQ9cSh"))
-            .build()
-            .context("building search query")?;
-
-    debug!(?req);
-
-    let response = client
-      .execute(req)
-      .context("querying the elasticsearch API")?;
-    let elapsed = then.elapsed();
-    debug!(?elapsed);
-    trace!(?response);
-
-    if !response.status().is_success() {
-      eprintln!(
-        "Error: search.nixos.org returned HTTP {} for channel '{}'. This \
-         usually means the channel does not exist, is not indexed, or the \
-         request was malformed.",
-        response.status(),
-        self.channel
-      );
-      return Err(color_eyre::eyre::eyre!(
-        "search.nixos.org returned HTTP {} for channel '{}'",
-        response.status(),
-        self.channel
-      ));
-    }
-
-    if !self.json {
-      println!("Took {}ms", elapsed.as_millis());
-      println!("Most relevant results at the end");
-      println!();
-    }
-
-    let parsed_response: SearchResponse = response
-      .json()
-      .context("parsing response into the elasticsearch format")?;
-    trace!(?parsed_response);
-
-    let documents = parsed_response
-      .documents::<SearchResult>()
-      .context("parsing search document")?;
+    let (documents, elapsed) = query_backend(&channel, &query)?;
 
     if self.json {
       // Output as JSON
@@ -195,6 +135,10 @@ impl SearchArgs {
       return Ok(());
     }
 
+    println!("Took {}ms", elapsed.as_millis());
+    println!("Most relevant results at the end");
+    println!();
+
     let hyperlinks = supports_hyperlinks::supports_hyperlinks();
     debug!(?hyperlinks);
 
@@ -264,6 +208,110 @@ impl SearchArgs {
   }
 }
 
+/// Returns the attribute names of packages in `channel` whose
+/// `package_programs` contain `program`, most relevant first.
+pub(crate) fn packages_providing(
+  channel: &str,
+  program: &str,
+  limit: u64,
+) -> Result<Vec<String>> {
+  let channel = resolve_channel(channel)?;
+
+  let query = Search::new().from(0).size(limit).query(
+    Query::bool()
+      .filter(Query::term("type", "package"))
+      .filter(Query::term("package_programs", program)),
+  );
+
+  let (documents, elapsed) = query_backend(&channel, &query)?;
+  debug!(?elapsed, count = documents.len());
+
+  Ok(
+    documents
+      .into_iter()
+      .map(|document| document.package_attr_name)
+      .collect(),
+  )
+}
+
+/// Maps deprecated channels to `nixos-unstable` and rejects channels that
+/// search.nixos.org does not index.
+fn resolve_channel(channel: &str) -> Result<String> {
+  let mut channel = channel.to_string();
+  if DEPRECATED_VERSIONS.contains(&channel.as_str()) {
+    warn!(
+      "Channel '{channel}' is deprecated or unavailable, falling back to \
+       'nixos-unstable'"
+    );
+    channel = "nixos-unstable".to_string();
+  }
+  if !supported_branch(&channel) {
+    bail!("Channel {channel} is not supported!");
+  }
+
+  Ok(channel)
+}
+
+/// Sends `query` to the search.nixos.org backend and returns the matching
+/// package documents along with the time the request took.
+fn query_backend(
+  channel: &str,
+  query: &Search,
+) -> Result<(Vec<SearchResult>, Duration)> {
+  let then = Instant::now();
+
+  let client = reqwest::blocking::Client::new();
+  let req = client
+            // NOTE: when the version of the backend API changes,
+            // this file and the corresponding workflow called
+            // nixos-search.yaml have to be updated accordingly.
+            .post(format!(
+                "https://search.nixos.org/backend/latest-43-{channel}/_search"
+            ))
+            .json(query)
+            .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
+            // Hardcoded upstream
+            // https://github.com/NixOS/nixos-search/blob/744ec58e082a3fcdd741b2c9b0654a0f7fda4603/frontend/src/index.js
+            .basic_auth("aWVSALXpZv", Some("X8gPHnzL52wFEekuxsfQ9cSh"))
+            .build()
+            .context("building search query")?;
+
+  debug!(?req);
+
+  let response = client
+    .execute(req)
+    .context("querying the elasticsearch API")?;
+  let elapsed = then.elapsed();
+  debug!(?elapsed);
+  trace!(?response);
+
+  if !response.status().is_success() {
+    eprintln!(
+      "Error: search.nixos.org returned HTTP {} for channel '{}'. This \
+       usually means the channel does not exist, is not indexed, or the \
+       request was malformed.",
+      response.status(),
+      channel
+    );
+    return Err(color_eyre::eyre::eyre!(
+      "search.nixos.org returned HTTP {} for channel '{}'",
+      response.status(),
+      channel
+    ));
+  }
+
+  let parsed_response: SearchResponse = response
+    .json()
+    .context("parsing response into the elasticsearch format")?;
+  trace!(?parsed_response);
+
+  let documents = parsed_response
+    .documents::<SearchResult>()
+    .context("parsing search document")?;
+
+  Ok((documents, elapsed))
+}
+
 fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
   let branch = branch.as_ref();
 
@@ -0,0 +1,382 @@
+//! SSH targets for remote builds and deployments.
+//!
+//! A target is given as `[user@]host[:port]` or as an `ssh://` or `ssh-ng://`
+//! store URI. Every ssh invocation for a target, including the ones Nix makes
+//! for `nix copy`, uses the options from `NIX_SSHOPTS`, and while an
+//! [`SshSession`] is alive they share a single master connection.
+use std::{
+  fmt,
+  path::{Path, PathBuf},
+  process,
+  str::FromStr,
+  sync::Mutex,
+};
+
+use color_eyre::eyre::{Context, bail};
+use tracing::debug;
+
+use crate::Result;
+
+/// The control socket directory of the running [`SshSession`], if any.
+static CONTROL_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// How long the master connection outlives its last client, so that the gaps
+/// between the steps of a deployment do not close it.
+const CONTROL_PERSIST: &str = "120";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SshScheme {
+  #[default]
+  Ssh,
+  SshNg,
+}
+
+/// A host to run commands on and copy store paths to over ssh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshTarget {
+  pub scheme: SshScheme,
+  pub user:   Option<String>,
+  pub host:   String,
+  pub port:   Option<u16>,
+}
+
+impl FromStr for SshTarget {
+  type Err = color_eyre::Report;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let (scheme, rest) = match s.split_once("://") {
+      Some(("ssh", rest)) => (SshScheme::Ssh, rest),
+      Some(("ssh-ng", rest)) => (SshScheme::SshNg, rest),
+      Some((scheme, _)) => {
+        bail!(
+          "Unsupported scheme '{scheme}://' in ssh target '{s}', expected \
+           ssh:// or ssh-ng://"
+        )
+      },
+      None => (SshScheme::default(), s),
+    };
+
+    // A trailing slash is allowed in URIs, but not a path
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    if rest.contains('/') {
+      bail!("Unexpected path in ssh target '{s}'");
+    }
+
+    let (user, authority) = match rest.split_once('@') {
+      Some((user, authority)) if !user.is_empty() => {
+        (Some(user.to_string()), authority)
+      },
+      Some(_) => bail!("Empty user name in ssh target '{s}'"),
+      None => (None, rest),
+    };
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+      // [IPv6 address] with an optional port
+      let Some((host, rest)) = bracketed.split_once(']') else {
+        bail!("Unterminated '[' in ssh target '{s}'");
+      };
+      match rest {
+        "" => (host, None),
+        _ => {
+          let Some(port) = rest.strip_prefix(':') else {
+            bail!("Unexpected '{rest}' after ']' in ssh target '{s}'");
+          };
+          (host, Some(port))
+        },
+      }
+    } else {
+      match authority.split_once(':') {
+        // A bare IPv6 address has several colons and no port
+        Some((host, port)) if !port.contains(':') => (host, Some(port)),
+        _ => (authority, None),
+      }
+    };
+
+    if host.is_empty() {
+      bail!("Missing host name in ssh target '{s}'");
+    }
+
+    let port = port
+      .map(|port| {
+        port
+          .parse::<u16>()
+          .ok()
+          .filter(|port| *port != 0)
+          .ok_or_else(|| {
+            color_eyre::eyre::eyre!("Invalid port '{port}' in ssh target '{s}'")
+          })
+      })
+      .transpose()?;
+
+    Ok(Self {
+      scheme,
+      user,
+      host: host.to_string(),
+      port,
+    })
+  }
+}
+
+impl fmt::Display for SshTarget {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.scheme == SshScheme::SshNg {
+      write!(f, "ssh-ng://")?;
+    }
+    if let Some(user) = &self.user {
+      write!(f, "{user}@")?;
+    }
+    if self.host.contains(':') {
+      write!(f, "[{}]", self.host)?;
+    } else {
+      write!(f, "{}", self.host)?;
+    }
+    if let Some(port) = self.port {
+      write!(f, ":{port}")?;
+    }
+    Ok(())
+  }
+}
+
+impl SshTarget {
+  /// Returns the `[user@]host` destination to pass to ssh.
+  pub fn destination(&self) -> String {
+    match &self.user {
+      Some(user) => format!("{user}@{}", self.host),
+      None => self.host.clone(),
+    }
+  }
+
+  /// Returns the Nix store URI of the target. The port is not part of it, as
+  /// Nix reads it from `NIX_SSHOPTS`, see [`SshTarget::nix_sshopts`].
+  pub fn store_uri(&self) -> String {
+    let scheme = match self.scheme {
+      SshScheme::Ssh => "ssh",
+      SshScheme::SshNg => "ssh-ng",
+    };
+    let host = if self.host.contains(':') {
+      format!("[{}]", self.host)
+    } else {
+      self.host.clone()
+    };
+    match &self.user {
+      Some(user) => format!("{scheme}://{user}@{host}"),
+      None => format!("{scheme}://{host}"),
+    }
+  }
+
+  /// Returns the options given to every ssh invocation for this target:
+  /// `NIX_SSHOPTS`, the shared master connection and the port.
+  pub fn ssh_options(&self) -> Vec<String> {
+    self.options_with(
+      &std::env::var("NIX_SSHOPTS").unwrap_or_default(),
+      control_dir().as_deref(),
+    )
+  }
+
+  fn options_with(
+    &self,
+    nix_sshopts: &str,
+    control_dir: Option<&Path>,
+  ) -> Vec<String> {
+    let mut options: Vec<String> =
+      nix_sshopts.split_whitespace().map(str::to_string).collect();
+
+    if let Some(dir) = control_dir {
+      options.extend([
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}", dir.join("%C").display()),
+        "-o".to_string(),
+        format!("ControlPersist={CONTROL_PERSIST}"),
+      ]);
+    }
+
+    if let Some(port) = self.port {
+      options.extend(["-p".to_string(), port.to_string()]);
+    }
+
+    options
+  }
+
+  /// Returns the arguments to `ssh` that run a command on the target without
+  /// a terminal, up to and including the destination.
+  pub fn ssh_args(&self) -> Vec<String> {
+    let mut args = self.ssh_options();
+    args.push("-T".to_string());
+    args.push(self.destination());
+    args
+  }
+
+  /// Returns the value of `NIX_SSHOPTS` for Nix commands that connect to the
+  /// target, such as `nix copy`.
+  pub fn nix_sshopts(&self) -> String {
+    self.ssh_options().join(" ")
+  }
+}
+
+fn control_dir() -> Option<PathBuf> {
+  CONTROL_DIR
+    .lock()
+    .unwrap_or_else(|e| e.into_inner())
+    .clone()
+}
+
+/// Shares one ssh master connection per target between all the steps of a
+/// deployment. The connections are closed when the session is dropped.
+#[derive(Debug)]
+pub struct SshSession {
+  dir:     tempfile::TempDir,
+  targets: Vec<SshTarget>,
+}
+
+impl SshSession {
+  /// Starts a session for `targets`. The master connection of a target is
+  /// opened by the first command that connects to it.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if the directory for the control sockets cannot be
+  /// created.
+  pub fn start<'a>(
+    targets: impl IntoIterator<Item = &'a SshTarget>,
+  ) -> Result<Self> {
+    let targets: Vec<SshTarget> = targets.into_iter().cloned().collect();
+
+    // Control socket paths are limited to about 100 bytes, so keep them short
+    let dir = tempfile::Builder::new()
+      .prefix("nh-ssh")
+      .tempdir_in("/tmp")
+      .context("Failed to create a directory for ssh control sockets")?;
+
+    debug!(dir = %dir.path().display(), "Sharing ssh connections");
+    *CONTROL_DIR.lock().unwrap_or_else(|e| e.into_inner()) =
+      Some(dir.path().to_path_buf());
+
+    Ok(Self { dir, targets })
+  }
+}
+
+impl Drop for SshSession {
+  fn drop(&mut self) {
+    for target in &self.targets {
+      let _ = process::Command::new("ssh")
+        .args(target.ssh_options())
+        .args(["-O", "exit", &target.destination()])
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status();
+    }
+
+    debug!(dir = %self.dir.path().display(), "Closed ssh connections");
+    *CONTROL_DIR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serial_test::serial;
+
+  use super::*;
+
+  fn parse(s: &str) -> SshTarget {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn test_parse_ssh_target() {
+    assert_eq!(parse("host"), SshTarget {
+      scheme: SshScheme::Ssh,
+      user:   None,
+      host:   "host".to_string(),
+      port:   None,
+    });
+    assert_eq!(parse("root@host:2222"), SshTarget {
+      scheme: SshScheme::Ssh,
+      user:   Some("root".to_string()),
+      host:   "host".to_string(),
+      port:   Some(2222),
+    });
+    assert_eq!(parse("ssh-ng://deploy@10.0.0.1:22/"), SshTarget {
+      scheme: SshScheme::SshNg,
+      user:   Some("deploy".to_string()),
+      host:   "10.0.0.1".to_string(),
+      port:   Some(22),
+    });
+    assert_eq!(parse("ssh://[fe80::1]:2222").host, "fe80::1");
+    assert_eq!(parse("fe80::1").port, None);
+
+    for invalid in [
+      "",
+      "user@",
+      "@host",
+      "host:0",
+      "host:port",
+      "https://host",
+      "ssh://host/path",
+      "[::1",
+    ] {
+      assert!(invalid.parse::<SshTarget>().is_err(), "{invalid}");
+    }
+  }
+
+  #[test]
+  fn test_ssh_target_round_trip() {
+    for target in [
+      "host",
+      "root@host:2222",
+      "[fe80::1]:22",
+      "user@[::1]",
+      "ssh-ng://host:22",
+    ] {
+      assert_eq!(parse(target).to_string(), target);
+    }
+  }
+
+  #[test]
+  fn test_store_uri() {
+    assert_eq!(parse("root@host:2222").store_uri(), "ssh://root@host");
+    assert_eq!(parse("ssh-ng://host").store_uri(), "ssh-ng://host");
+    assert_eq!(
+      parse("root@[fe80::1]:22").store_uri(),
+      "ssh://root@[fe80::1]"
+    );
+  }
+
+  #[test]
+  fn test_ssh_options() {
+    let target = parse("root@host:2222");
+    assert_eq!(
+      target.options_with("-i  /key -o StrictHostKeyChecking=no", None),
+      ["-i", "/key", "-o", "StrictHostKeyChecking=no", "-p", "2222"]
+    );
+
+    let options = parse("host").options_with("", Some(Path::new("/tmp/nh")));
+    assert_eq!(options, [
+      "-o",
+      "ControlMaster=auto",
+      "-o",
+      "ControlPath=/tmp/nh/%C",
+      "-o",
+      "ControlPersist=120"
+    ]);
+  }
+
+  #[test]
+  #[serial]
+  fn test_session_shares_connections() {
+    let target = parse("host");
+    let session = SshSession::start([]).unwrap();
+    let dir = session.dir.path().to_path_buf();
+    assert!(
+      target
+        .ssh_options()
+        .contains(&format!("ControlPath={}", dir.join("%C").display()))
+    );
+
+    drop(session);
+    assert!(!dir.exists());
+    assert!(control_dir().is_none());
+  }
+}
@@ -1,13 +1,105 @@
-use tracing::warn;
+use std::{
+  fs,
+  io,
+  path::{Path, PathBuf},
+};
 
-use crate::{Result, commands::Command, installable::Installable};
+use color_eyre::eyre::{Context, bail};
+use nix::unistd::Uid;
+use owo_colors::OwoColorize;
+use tracing::{debug, error, info, warn};
 
+use crate::{
+  Result,
+  commands::{Command, ElevationStrategy},
+  flake::{self, FlakeLock, InputChange},
+  installable::{FlakeRefKind, Installable},
+  interface::UpdateArgs,
+};
+
+/// The channels profile of the root user, which `NixOS` builds from.
+const ROOT_CHANNELS: &str = "/nix/var/nix/profiles/per-user/root/channels";
+
+/// Channels containing this file are updated along with the `nixos` channel,
+/// like `nixos-rebuild --upgrade` does.
+const UPDATE_ON_REBUILD: &str = ".update-on-nixos-rebuild";
+
+/// Updates the inputs of a flake, or the channels used by a file or
+/// expression installable, if requested and prints what changed.
+///
+/// Channels are updated for root if `elevation` is given, and for the current
+/// user otherwise.
+///
+/// For flakes, returns a snapshot of the previous `flake.lock` that restores
+/// it when dropped. Callers should hold on to it while building and
+/// activating, and call [`LockSnapshot::keep`] once they succeeded.
 pub fn update(
+  installable: &Installable,
+  args: UpdateArgs,
+  elevation: Option<ElevationStrategy>,
+) -> Result<Option<LockSnapshot>> {
+  if !args.update_all && args.update_input.is_none() {
+    return Ok(None);
+  }
+
+  let snapshot = match installable {
+    Installable::Flake { reference, .. } => {
+      reference
+        .flake_dir()
+        .map(|dir| {
+          LockSnapshot::take(&dir.join("flake.lock"), args.keep_failed_update)
+        })
+        .transpose()?
+    },
+    Installable::File { .. } | Installable::Expression { .. } => {
+      update_channels(args.update_input, elevation)?;
+      return Ok(None);
+    },
+    Installable::Store { .. } => None,
+  };
+
+  if let Some(changes) = update_inputs(installable, args.update_input)? {
+    flake::print_changes(&changes);
+  }
+
+  Ok(snapshot)
+}
+
+/// Updates the inputs of a flake, returning how its inputs changed.
+///
+/// Returns `None` if the lock file could not be compared, e.g. because the
+/// flake is resolved through the registry and its lock file is not known.
+pub fn update_inputs(
   installable: &Installable,
   inputs: Option<Vec<String>>,
-) -> Result<()> {
+) -> Result<Option<Vec<InputChange>>> {
   match installable {
     Installable::Flake { reference, .. } => {
+      // Indirect references may resolve to a local flake through the registry,
+      // so leave it to Nix to decide whether those can be updated.
+      if !reference.is_local()
+        && !matches!(reference.kind(), FlakeRefKind::Indirect { .. })
+      {
+        bail!(
+          "Cannot update the inputs of {reference}, only local flakes have a \
+           writable flake.lock"
+        );
+      }
+
+      let flake_dir = reference.flake_dir();
+      if let Some(flake_dir) = &flake_dir {
+        if !flake_dir.join("flake.nix").exists() {
+          bail!("No flake.nix found in {}", flake_dir.display());
+        }
+      }
+
+      let lock_path = flake_dir.map(|dir| dir.join("flake.lock"));
+
+      let old_lock = match &lock_path {
+        Some(path) => FlakeLock::read_if_exists(path)?,
+        None => None,
+      };
+
       let mut cmd = Command::new("nix").args(["flake", "update"]);
 
       if let Some(inputs) = inputs {
@@ -23,15 +115,329 @@ pub fn update(
         cmd = cmd.message("Updating all flake inputs");
       }
 
-      cmd.arg("--flake").arg(reference).run()?;
+      cmd.arg("--flake").arg(reference.as_str()).run()?;
+
+      let Some(lock_path) = lock_path else {
+        debug!("Not comparing lock files of {reference}, it is not local");
+        return Ok(None);
+      };
+
+      let new_lock = FlakeLock::read(&lock_path)?;
+      Ok(Some(flake::diff_locks(old_lock.as_ref(), &new_lock)))
     },
     _ => {
       warn!(
-        "Only flake installables can be updated, {} is not supported",
+        "Only flakes and channels can be updated, {} is not supported",
         installable.str_kind()
       );
+      Ok(None)
     },
   }
+}
+
+/// Updates channels the way `nixos-rebuild --upgrade` does and prints the
+/// version change of every updated channel.
+///
+/// Without an explicit list, root updates its `nixos` channel and the channels
+/// marked with `.update-on-nixos-rebuild`, or none if it has neither, while
+/// other users update all their channels.
+fn update_channels(
+  channels: Option<Vec<String>>,
+  elevation: Option<ElevationStrategy>,
+) -> Result<()> {
+  let as_root = elevation.is_some() || Uid::effective().is_root();
+  let channels_dir = if as_root {
+    PathBuf::from(ROOT_CHANNELS)
+  } else {
+    PathBuf::from(std::env::var("HOME")?).join(".nix-defexpr/channels")
+  };
+
+  let channels = match channels {
+    Some(channels) => channels,
+    None if as_root => {
+      let channels = system_channels(&channels_dir);
+      // An empty list would update every channel of root instead
+      if channels.is_empty() {
+        warn!(
+          "Not updating any channel, as root has no nixos channel nor one \
+           marked with {UPDATE_ON_REBUILD} in {}",
+          channels_dir.display()
+        );
+        return Ok(());
+      }
+      channels
+    },
+    None => Vec::new(),
+  };
+
+  // An empty list makes nix-channel update every channel, so compare all of
+  // them in that case.
+  let compared = if channels.is_empty() {
+    list_channels(&channels_dir)
+  } else {
+    channels.clone()
+  };
+
+  let before: Vec<_> = compared
+    .iter()
+    .map(|channel| channel_version(&channels_dir.join(channel)))
+    .collect();
+
+  let message = if channels.is_empty() {
+    "Updating all channels".to_string()
+  } else {
+    format!(
+      "Updating channel{maybe_plural} {channels}",
+      maybe_plural = if channels.len() > 1 { "s" } else { "" },
+      channels = channels.join(", ")
+    )
+  };
+
+  Command::new("nix-channel")
+    .arg("--update")
+    .args(&channels)
+    .elevate(elevation)
+    .message(message)
+    .run()?;
+
+  for (channel, before) in compared.iter().zip(before) {
+    let after = channel_version(&channels_dir.join(channel));
+    let unknown = || "unknown".to_string();
+
+    if before == after {
+      println!(
+        "{}  {} (unchanged)",
+        channel.bold(),
+        after.unwrap_or_else(unknown)
+      );
+    } else {
+      println!(
+        "{}  {} -> {}",
+        channel.bold(),
+        before.unwrap_or_else(unknown).red(),
+        after.unwrap_or_else(unknown).green()
+      );
+    }
+  }
 
   Ok(())
 }
+
+/// Returns the channels root updates by default: `nixos` and those marked with
+/// `.update-on-nixos-rebuild`.
+fn system_channels(channels_dir: &Path) -> Vec<String> {
+  list_channels(channels_dir)
+    .into_iter()
+    .filter(|channel| {
+      channel == "nixos"
+        || channels_dir.join(channel).join(UPDATE_ON_REBUILD).exists()
+    })
+    .collect()
+}
+
+/// Lists the channels in a channels profile, sorted by name.
+fn list_channels(channels_dir: &Path) -> Vec<String> {
+  let Ok(entries) = fs::read_dir(channels_dir) else {
+    debug!("Could not read channels from {}", channels_dir.display());
+    return Vec::new();
+  };
+
+  let mut channels: Vec<_> = entries
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.path().is_dir())
+    .filter_map(|entry| entry.file_name().into_string().ok())
+    .filter(|name| !name.starts_with('.'))
+    .collect();
+  channels.sort();
+  channels
+}
+
+/// Returns a human readable version of a channel, e.g. `25.05.804.a1b2c3d`
+/// for nixpkgs channels, or the name of its store path for other channels.
+fn channel_version(channel: &Path) -> Option<String> {
+  if let Ok(version) = fs::read_to_string(channel.join(".version")) {
+    let suffix =
+      fs::read_to_string(channel.join(".version-suffix")).unwrap_or_default();
+    return Some(format!("{}{}", version.trim(), suffix.trim()));
+  }
+
+  let store_path = channel.canonicalize().ok()?;
+  let name = store_path.parent()?.file_name()?.to_str()?;
+  // Strip the store path hash
+  Some(
+    name
+      .split_once('-')
+      .map_or(name, |(_, name)| name)
+      .to_string(),
+  )
+}
+
+/// The contents of a `flake.lock` from before an update.
+///
+/// Dropping the snapshot writes the old contents back, so that an update
+/// followed by a failed build or activation leaves the flake as it was.
+#[must_use = "dropping the snapshot restores flake.lock immediately"]
+#[derive(Debug)]
+pub struct LockSnapshot {
+  path:            PathBuf,
+  /// `None` if the flake had no lock file before the update
+  contents:        Option<Vec<u8>>,
+  keep_on_failure: bool,
+  kept:            bool,
+}
+
+impl LockSnapshot {
+  fn take(path: &Path, keep_on_failure: bool) -> Result<Self> {
+    let contents = match fs::read(path) {
+      Ok(contents) => Some(contents),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+      Err(err) => {
+        return Err(err).wrap_err_with(|| {
+          format!("Failed to snapshot {} before updating", path.display())
+        });
+      },
+    };
+
+    debug!("Took a snapshot of {}", path.display());
+
+    Ok(Self {
+      path: path.to_path_buf(),
+      contents,
+      keep_on_failure,
+      kept: false,
+    })
+  }
+
+  /// Keeps the updated lock file, to be called once the rebuild succeeded.
+  pub fn keep(mut self) {
+    debug!("Keeping the updated {}", self.path.display());
+    self.kept = true;
+  }
+
+  fn restore(&self) -> io::Result<()> {
+    match &self.contents {
+      Some(contents) => fs::write(&self.path, contents),
+      None => {
+        match fs::remove_file(&self.path) {
+          Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+          _ => Ok(()),
+        }
+      },
+    }
+  }
+}
+
+impl Drop for LockSnapshot {
+  fn drop(&mut self) {
+    if self.kept {
+      return;
+    }
+
+    if self.keep_on_failure {
+      warn!(
+        "The rebuild did not complete, keeping the updated {} as \
+         --keep-failed-update was passed",
+        self.path.display()
+      );
+      return;
+    }
+
+    match self.restore() {
+      Ok(()) => {
+        info!(
+          "The rebuild did not complete, restored {} to its state before the \
+           update (pass --keep-failed-update to keep the updated lock file)",
+          self.path.display()
+        );
+      },
+      Err(err) => {
+        error!(
+          "The rebuild did not complete and {} could not be restored: {err}",
+          self.path.display()
+        );
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_channel_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let nixos = dir.path().join("nixos");
+    fs::create_dir(&nixos).unwrap();
+
+    fs::write(nixos.join(".version"), "25.05\n").unwrap();
+    assert_eq!(channel_version(&nixos).as_deref(), Some("25.05"));
+
+    fs::write(nixos.join(".version-suffix"), ".804.a1b2c3d").unwrap();
+    assert_eq!(
+      channel_version(&nixos).as_deref(),
+      Some("25.05.804.a1b2c3d")
+    );
+  }
+
+  #[test]
+  fn test_system_channels() {
+    let dir = tempfile::tempdir().unwrap();
+    for channel in ["nixos", "home-manager", "nixos-hardware"] {
+      fs::create_dir(dir.path().join(channel)).unwrap();
+    }
+    fs::write(dir.path().join("manifest.nix"), "[]").unwrap();
+    fs::write(
+      dir.path().join("nixos-hardware").join(UPDATE_ON_REBUILD),
+      "",
+    )
+    .unwrap();
+
+    assert_eq!(list_channels(dir.path()), vec![
+      "home-manager",
+      "nixos",
+      "nixos-hardware"
+    ]);
+    assert_eq!(system_channels(dir.path()), vec!["nixos", "nixos-hardware"]);
+  }
+
+  #[test]
+  fn test_lock_snapshot_restores_on_drop() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flake.lock");
+    fs::write(&path, "old").unwrap();
+
+    let snapshot = LockSnapshot::take(&path, false).unwrap();
+    fs::write(&path, "new").unwrap();
+    drop(snapshot);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+    let snapshot = LockSnapshot::take(&path, false).unwrap();
+    fs::write(&path, "new").unwrap();
+    snapshot.keep();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+  }
+
+  #[test]
+  fn test_lock_snapshot_keep_on_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flake.lock");
+    fs::write(&path, "old").unwrap();
+
+    let snapshot = LockSnapshot::take(&path, true).unwrap();
+    fs::write(&path, "new").unwrap();
+    drop(snapshot);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+  }
+
+  #[test]
+  fn test_lock_snapshot_removes_new_lock_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flake.lock");
+
+    let snapshot = LockSnapshot::take(&path, false).unwrap();
+    fs::write(&path, "new").unwrap();
+    drop(snapshot);
+    assert!(!path.exists());
+  }
+}
@@ -12,7 +12,10 @@ use color_eyre::{Result, eyre};
 use regex::Regex;
 use tracing::{debug, info};
 
-use crate::commands::{Command, ElevationStrategy};
+use crate::{
+  commands::{self, Command, ElevationStrategy},
+  ssh::SshTarget,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NixVariant {
@@ -288,6 +291,23 @@ pub fn self_elevate(strategy: ElevationStrategy) -> ! {
   panic!("{}", err);
 }
 
+/// Copies `path` and its closure from `target` into the local store, so that
+/// it can be diffed against. Does nothing if the path is already here.
+///
+/// # Errors
+///
+/// Returns an error if `nix copy` fails, for example because the paths are
+/// not signed by a key this store trusts.
+pub fn copy_from_remote(target: &SshTarget, path: &Path) -> Result<()> {
+  if path.exists() {
+    return Ok(());
+  }
+  commands::Copy::from(target)
+    .path(path)
+    .message(format!("Copying {} from {target}", path.display()))
+    .run()
+}
+
 /// Prints the difference between two generations in terms of paths and closure
 /// sizes.
 ///
@@ -0,0 +1,326 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{Context, bail};
+use owo_colors::OwoColorize;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::{Result, commands::Command, interface::WhichArgs, search};
+
+/// Location of the `programs.sqlite` database shipped with the root user's
+/// `nixos` channel. This is what `command-not-found` reads on `NixOS`.
+const CHANNEL_PROGRAMS_DB: &str =
+  "/nix/var/nix/profiles/per-user/root/channels/nixos/programs.sqlite";
+
+/// Where a candidate package was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Source {
+  /// The `programs.sqlite` database from a nixos channel
+  ProgramsDatabase,
+  /// A nix-index database, queried through `nix-locate`
+  NixIndex,
+  /// The `package_programs` field of search.nixos.org
+  SearchBackend,
+}
+
+impl Source {
+  const fn describe(self) -> &'static str {
+    match self {
+      Self::ProgramsDatabase => "programs.sqlite",
+      Self::NixIndex => "nix-index",
+      Self::SearchBackend => "search.nixos.org",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Candidate {
+  attribute: String,
+  source:    Source,
+}
+
+#[derive(Debug, Serialize)]
+struct JSONOutput<'a> {
+  program: &'a str,
+  system:  &'a str,
+  results: Vec<Candidate>,
+}
+
+impl WhichArgs {
+  pub fn run(&self) -> Result<()> {
+    if self.program.is_empty() || self.program.contains('/') {
+      bail!("'{}' is not a valid program name", self.program);
+    }
+
+    let system = current_system();
+    debug!(?system, program = ?self.program);
+
+    let mut candidates = Vec::new();
+
+    match self.programs_database() {
+      Some(database) => {
+        match query_programs_database(&database, &self.program, &system) {
+          Ok(found) => {
+            candidates.extend(found.into_iter().map(|attribute| {
+              Candidate {
+                attribute,
+                source: Source::ProgramsDatabase,
+              }
+            }));
+          },
+          Err(err) => warn!("{err:#}"),
+        }
+      },
+      None => debug!("No programs.sqlite database found"),
+    }
+
+    match query_nix_index(&self.program) {
+      Ok(found) => {
+        candidates.extend(found.into_iter().map(|attribute| {
+          Candidate {
+            attribute,
+            source: Source::NixIndex,
+          }
+        }));
+      },
+      Err(err) => debug!("Skipping nix-index lookup: {err:#}"),
+    }
+
+    if candidates.is_empty() && !self.offline {
+      if !self.json {
+        println!(
+          "No local database knows about '{}', querying search.nixos.org with \
+           channel {}...",
+          self.program, self.channel
+        );
+      }
+      let found =
+        search::packages_providing(&self.channel, &self.program, self.limit)?;
+      candidates.extend(found.into_iter().map(|attribute| {
+        Candidate {
+          attribute,
+          source: Source::SearchBackend,
+        }
+      }));
+    }
+
+    let mut candidates = rank_candidates(&self.program, candidates);
+    candidates.truncate(usize::try_from(self.limit).unwrap_or(usize::MAX));
+
+    if self.json {
+      let json_output = JSONOutput {
+        program: &self.program,
+        system:  &system,
+        results: candidates,
+      };
+
+      println!("{}", serde_json::to_string_pretty(&json_output)?);
+      return Ok(());
+    }
+
+    if candidates.is_empty() {
+      bail!("No package providing '{}' was found", self.program);
+    }
+
+    println!("The program '{}' is provided by:", self.program.bold());
+    for candidate in &candidates {
+      println!(
+        "  {} ({})",
+        candidate.attribute.blue(),
+        candidate.source.describe()
+      );
+    }
+
+    println!();
+    println!("Try it with: nix shell nixpkgs#{}", candidates[0].attribute);
+
+    Ok(())
+  }
+
+  /// Returns the `programs.sqlite` database to read, if one is available.
+  fn programs_database(&self) -> Option<PathBuf> {
+    if let Some(database) = &self.database {
+      return Some(database.clone());
+    }
+
+    let default = PathBuf::from(CHANNEL_PROGRAMS_DB);
+    default.exists().then_some(default)
+  }
+}
+
+/// Returns the Nix system double for the platform nh was built for, e.g.
+/// `x86_64-linux`.
+fn current_system() -> String {
+  let os = match std::env::consts::OS {
+    "macos" => "darwin",
+    os => os,
+  };
+  format!("{}-{os}", std::env::consts::ARCH)
+}
+
+/// Looks up `program` in a `programs.sqlite` database, as used by
+/// `command-not-found`.
+fn query_programs_database(
+  database: &Path,
+  program: &str,
+  system: &str,
+) -> Result<Vec<String>> {
+  let connection =
+    Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+      .with_context(|| {
+        format!("Failed to open programs database {}", database.display())
+      })?;
+
+  let mut statement = connection
+    .prepare(
+      "SELECT package FROM Programs WHERE name = ?1 AND system = ?2 ORDER BY \
+       package",
+    )
+    .context("Failed to query programs database")?;
+
+  let packages = statement
+    .query_map([program, system], |row| row.get::<_, String>(0))?
+    .collect::<rusqlite::Result<Vec<_>>>()
+    .context("Failed to read programs database")?;
+
+  debug!(?packages, "programs.sqlite results");
+
+  Ok(packages)
+}
+
+/// Looks up `program` through `nix-locate`, if nix-index is installed.
+fn query_nix_index(program: &str) -> Result<Vec<String>> {
+  which::which("nix-locate").context("nix-locate is not installed")?;
+
+  let output = Command::new("nix-locate")
+    .args(["--minimal", "--top-level", "--whole-name", "--at-root"])
+    .arg(format!("/bin/{program}"))
+    .run_capture()?
+    .unwrap_or_default();
+
+  Ok(parse_nix_locate(&output))
+}
+
+/// Parses `nix-locate --minimal` output, which prints one `attribute.output`
+/// per line, into attribute paths.
+fn parse_nix_locate(output: &str) -> Vec<String> {
+  output
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .map(|line| {
+      line
+        .rsplit_once('.')
+        .map_or(line, |(attribute, _output)| attribute)
+        .to_string()
+    })
+    .collect()
+}
+
+/// Deduplicates candidates and orders them so that attributes named exactly
+/// like the program come first, followed by top-level attributes before
+/// nested ones. The relative order of each source is kept otherwise.
+fn rank_candidates(
+  program: &str,
+  candidates: Vec<Candidate>,
+) -> Vec<Candidate> {
+  let mut unique: Vec<Candidate> = Vec::with_capacity(candidates.len());
+  for candidate in candidates {
+    if !unique.iter().any(|c| c.attribute == candidate.attribute) {
+      unique.push(candidate);
+    }
+  }
+
+  unique.sort_by_key(|candidate| {
+    let name = candidate
+      .attribute
+      .rsplit('.')
+      .next()
+      .unwrap_or(&candidate.attribute);
+    (name != program, candidate.attribute.matches('.').count())
+  });
+
+  unique
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn candidate(attribute: &str, source: Source) -> Candidate {
+    Candidate {
+      attribute: attribute.to_string(),
+      source,
+    }
+  }
+
+  #[test]
+  fn test_parse_nix_locate() {
+    let output = "ripgrep.out\npython312Packages.ripgrepy.out\n\n";
+    assert_eq!(parse_nix_locate(output), vec![
+      "ripgrep",
+      "python312Packages.ripgrepy"
+    ]);
+  }
+
+  #[test]
+  fn test_rank_candidates_prefers_exact_matches() {
+    let ranked = rank_candidates("hello", vec![
+      candidate("python3Packages.hello", Source::ProgramsDatabase),
+      candidate("gnu-hello", Source::ProgramsDatabase),
+      candidate("hello", Source::ProgramsDatabase),
+    ]);
+
+    let attributes: Vec<_> =
+      ranked.iter().map(|c| c.attribute.as_str()).collect();
+    assert_eq!(attributes, vec![
+      "hello",
+      "python3Packages.hello",
+      "gnu-hello"
+    ]);
+  }
+
+  #[test]
+  fn test_rank_candidates_deduplicates_across_sources() {
+    let ranked = rank_candidates("rg", vec![
+      candidate("ripgrep", Source::ProgramsDatabase),
+      candidate("ripgrep", Source::NixIndex),
+      candidate("ripgrep-all", Source::NixIndex),
+    ]);
+
+    assert_eq!(ranked, vec![
+      candidate("ripgrep", Source::ProgramsDatabase),
+      candidate("ripgrep-all", Source::NixIndex),
+    ]);
+  }
+
+  #[test]
+  fn test_query_programs_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = dir.path().join("programs.sqlite");
+
+    let connection = Connection::open(&database).unwrap();
+    connection
+      .execute_batch(
+        "CREATE TABLE Programs (name text not null, system text not null, \
+         package text not null, primary key (name, system, package));
+         INSERT INTO Programs VALUES ('rg', 'x86_64-linux', 'ripgrep');
+         INSERT INTO Programs VALUES ('rg', 'aarch64-linux', 'ripgrep');
+         INSERT INTO Programs VALUES ('rga', 'x86_64-linux', 'ripgrep-all');",
+      )
+      .unwrap();
+    drop(connection);
+
+    assert_eq!(
+      query_programs_database(&database, "rg", "x86_64-linux").unwrap(),
+      vec!["ripgrep"]
+    );
+    assert!(
+      query_programs_database(&database, "rg", "x86_64-darwin")
+        .unwrap()
+        .is_empty()
+    );
+  }
+}